@@ -0,0 +1,46 @@
+#![feature(type_ascription, async_closure)]
+
+use std::{error::Error, time::Instant};
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::{self, net::UnixStream};
+use protociolla::{self, Reframed, Sessions, Packet, packet::Cookie, shm};
+
+const PACKETS: usize = 100_000;
+const SIZES: &[usize] = &[64, 1024, 16 * 1024, 64 * 1024];
+
+async fn run(name: &str, size: usize, mut left: Reframed<Sessions>, mut right: Reframed<Sessions>) -> Result<(), Box<dyn Error>> {
+	let payload = Bytes::from(vec![0x42; size]);
+	let start = Instant::now();
+
+	tokio::spawn(async move {
+		for _ in 0 .. PACKETS {
+			left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+		}
+	});
+
+	for _ in 0 .. PACKETS {
+		let mut session = right.next().await.unwrap()?;
+		session.next().await.unwrap();
+	}
+
+	let elapsed = start.elapsed();
+	let throughput = (PACKETS * size) as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+
+	println!("{:>6} {:>6}B: {:>10.2?} ({:.2} MiB/s)", name, size, elapsed, throughput);
+	Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+	for &size in SIZES {
+		let (left, right) = UnixStream::pair()?;
+		run("unix", size, protociolla::mi(left), protociolla::mi(right)).await?;
+
+		let (left, fds) = shm::Shared::create(shm::CAPACITY)?;
+		let right = shm::Shared::open(fds)?;
+		run("shm", size, shm::mi(left), shm::mi(right)).await?;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,26 @@
+#![feature(type_ascription, async_closure)]
+
+use std::error::Error;
+use futures::{stream::StreamExt, sink::SinkExt};
+use protociolla::{Packet, packet::Cookie, process};
+
+/// Echo every message back to the parent process until told to exit.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+	let mut packets = process::plugin::<()>();
+	eprintln!("plugin started");
+
+	while let Some(session) = packets.next().await {
+		let mut session = session?;
+
+		while let Some(message) = session.next().await {
+			if &message.bytes()[..] == b"exit" {
+				return Ok(());
+			}
+
+			packets.send(Packet::new(Cookie::Oneshot, message.bytes().clone())).await?;
+		}
+	}
+
+	Ok(())
+}
@@ -0,0 +1,59 @@
+#![feature(type_ascription, async_closure)]
+
+use std::error::Error;
+use futures::{stream::StreamExt, sink::SinkExt};
+use protociolla::{self, Message, Packet, format};
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Request {
+	Status,
+	Reload,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Status {
+	pub uptime: u64,
+	pub healthy: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+	// The agent connects, but it's the server asking the questions.
+	let (mut agent, mut server) = protociolla::pair::<format::MessagePack>();
+
+	tokio::spawn(async move {
+		while let Some(Ok(mut session)) = agent.next().await {
+			tokio::spawn(async move {
+				while let Some(message) = session.next().await {
+					match message.cast::<Request>() {
+						Ok(Request::Status) => {
+							let status = Status { uptime: 42, healthy: true };
+							session.send(Message::end(&status).unwrap()).await.unwrap();
+						}
+
+						Ok(Request::Reload) => {
+							println!("agent: reloading configuration");
+						}
+
+						Err(err) => {
+							eprintln!("agent: {}", err);
+						}
+					}
+				}
+			});
+		}
+	});
+
+	// Requests that take no reply don't need a session.
+	server.send(Packet::oneshot(&Request::Reload)?).await?;
+
+	let mut session = server.open()?;
+	session.send(Message::more(&Request::Status)?).await?;
+
+	if let Some(reply) = session.next().await {
+		println!("server: {:?}", reply.cast::<Status>()?);
+	}
+
+	Ok(())
+}
@@ -0,0 +1,359 @@
+//! Authentication of peers before any session starts.
+//!
+//! The accepting side runs an `Authenticator`, sending challenges the
+//! connecting side answers with its `Credentials`, until it either accepts the
+//! peer with an `Identity` or rejects it. The identity is then attached to
+//! every incoming `Session`.
+
+use std::{io, sync::Arc};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::{codec::Framed, io::{AsyncRead, AsyncWrite}};
+use crate::{Format, Reframed, Codec, Packets, Sessions, Role, packet::{self, Packet}, session::Peer};
+
+/// The server is challenging the client.
+const CHALLENGE: u8 = 0;
+
+/// The client is responding to a challenge.
+const RESPONSE: u8 = 1;
+
+/// The client has been accepted.
+const ACCEPT: u8 = 2;
+
+/// The client has been rejected.
+const REJECT: u8 = 3;
+
+/// An authenticated identity.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Identity {
+	/// The mechanism used to authenticate.
+	pub mechanism: String,
+
+	/// The name the peer authenticated as.
+	pub name: String,
+}
+
+/// The outcome of verifying a response.
+pub enum Verdict {
+	/// Another challenge for the client.
+	Challenge(Bytes),
+
+	/// The client is who it says it is.
+	Accept(Identity),
+}
+
+/// The accepting side of an authentication mechanism.
+pub trait Authenticator: Send {
+	/// The name of the mechanism.
+	fn mechanism(&self) -> &str;
+
+	/// The first challenge to send.
+	fn challenge(&mut self) -> Result<Bytes, io::Error>;
+
+	/// Verify a response to the last challenge, an error rejects the client.
+	fn verify(&mut self, response: &[u8]) -> Result<Verdict, io::Error>;
+}
+
+/// The connecting side of an authentication mechanism.
+pub trait Credentials: Send {
+	/// The name of the mechanism.
+	fn mechanism(&self) -> &str;
+
+	/// Respond to a challenge.
+	fn respond(&mut self, challenge: &[u8]) -> Result<Bytes, io::Error>;
+}
+
+fn rejected() -> io::Error {
+	io::Error::new(io::ErrorKind::PermissionDenied, "authentication rejected")
+}
+
+fn message(kind: u8, payload: &[u8]) -> Packet<()> {
+	let mut bytes = BytesMut::with_capacity(1 + payload.len());
+	bytes.put_u8(kind);
+	bytes.put_slice(payload);
+
+	Packet::new(packet::Cookie::Oneshot, bytes.freeze())
+}
+
+async fn receive<F: Format>(packets: &mut Reframed<Packets<F>>) -> Result<(u8, Bytes), io::Error> {
+	let packet = packets.next().await
+		.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+
+	if packet.bytes().is_empty() {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "empty authentication message"));
+	}
+
+	Ok((packet.bytes()[0], packet.bytes().slice_from(1)))
+}
+
+/// Authenticate the peer on a packet stream, no other packets must be sent
+/// until this is done.
+pub async fn verify<F, A>(packets: &mut Reframed<Packets<F>>, mut authenticator: A) -> Result<Identity, io::Error>
+	where F: Format,
+	      A: Authenticator
+{
+	let mechanism = authenticator.mechanism().as_bytes().to_vec();
+
+	if mechanism.len() > 0xff {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "mechanism name too long"));
+	}
+
+	let challenge = authenticator.challenge()?;
+
+	let mut first = BytesMut::with_capacity(1 + mechanism.len() + challenge.len());
+	first.put_u8(mechanism.len() as u8);
+	first.put_slice(&mechanism);
+	first.put_slice(&challenge);
+
+	packets.send(message(CHALLENGE, &first).with_format()).await?;
+
+	loop {
+		let (kind, response) = receive(packets).await?;
+
+		if kind != RESPONSE {
+			return Err(rejected());
+		}
+
+		match authenticator.verify(&response) {
+			Ok(Verdict::Challenge(challenge)) => {
+				packets.send(message(CHALLENGE, &challenge).with_format()).await?;
+			}
+
+			Ok(Verdict::Accept(identity)) => {
+				packets.send(message(ACCEPT, &[]).with_format()).await?;
+				return Ok(identity);
+			}
+
+			Err(error) => {
+				packets.send(message(REJECT, &[]).with_format()).await?;
+				return Err(error);
+			}
+		}
+	}
+}
+
+/// Authenticate to the peer on a packet stream, no other packets must be sent
+/// until this is done.
+pub async fn present<F, C>(packets: &mut Reframed<Packets<F>>, mut credentials: C) -> Result<(), io::Error>
+	where F: Format,
+	      C: Credentials
+{
+	let (kind, first) = receive(packets).await?;
+
+	if kind != CHALLENGE || first.is_empty() || first.len() < 1 + usize::from(first[0]) {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid authentication challenge"));
+	}
+
+	let length = usize::from(first[0]);
+	if &first[1 ..= length] != credentials.mechanism().as_bytes() {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported authentication mechanism"));
+	}
+
+	let mut challenge = first.slice_from(1 + length);
+
+	loop {
+		let response = credentials.respond(&challenge)?;
+		packets.send(message(RESPONSE, &response).with_format()).await?;
+
+		let (kind, next) = receive(packets).await?;
+
+		match kind {
+			CHALLENGE =>
+				challenge = next,
+
+			ACCEPT =>
+				return Ok(()),
+
+			_ =>
+				return Err(rejected()),
+		}
+	}
+}
+
+/// Authenticate the peer, then build the session stack attaching its
+/// identity to every `Session`.
+pub async fn accept<F, S, A>(socket: S, authenticator: A) -> Result<Reframed<Sessions<F>>, io::Error>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	      A: Authenticator
+{
+	let mut packets = Reframed::<Packets<F>>::new(Framed::new(socket, Codec::default()));
+	let identity = verify(&mut packets, authenticator).await?;
+	let peer = Arc::new(Peer { identity: Some(identity), .. Peer::default() });
+
+	Ok(packets.into_sessions_as(Role::Acceptor)
+		.map_stream(move |session| session.with_peer(peer.clone())))
+}
+
+/// Authenticate to the peer, then build the session stack.
+pub async fn connect<F, S, C>(socket: S, credentials: C) -> Result<Reframed<Sessions<F>>, io::Error>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	      C: Credentials
+{
+	let mut packets = Reframed::<Packets<F>>::new(Framed::new(socket, Codec::default()));
+	present(&mut packets, credentials).await?;
+
+	Ok(packets.into_sessions_as(Role::Initiator))
+}
+
+/// Bearer token authentication.
+pub mod bearer {
+	use std::io;
+	use bytes::Bytes;
+	use super::{Authenticator, Credentials, Identity, Verdict};
+
+	/// The name of the mechanism.
+	pub const MECHANISM: &str = "BEARER";
+
+	/// Accept clients presenting a token the validator recognizes.
+	pub struct Validator<V> {
+		validate: V,
+	}
+
+	impl<V> Validator<V>
+		where V: FnMut(&[u8]) -> Option<String> + Send
+	{
+		/// Create a validator, returning the name for a valid token.
+		pub fn new(validate: V) -> Self {
+			Self { validate }
+		}
+	}
+
+	impl<V> Authenticator for Validator<V>
+		where V: FnMut(&[u8]) -> Option<String> + Send
+	{
+		fn mechanism(&self) -> &str {
+			MECHANISM
+		}
+
+		fn challenge(&mut self) -> Result<Bytes, io::Error> {
+			Ok(Bytes::new())
+		}
+
+		fn verify(&mut self, response: &[u8]) -> Result<Verdict, io::Error> {
+			if let Some(name) = (self.validate)(response) {
+				Ok(Verdict::Accept(Identity {
+					mechanism: MECHANISM.into(),
+					name: name,
+				}))
+			}
+			else {
+				Err(super::rejected())
+			}
+		}
+	}
+
+	/// Present a token.
+	pub struct Token(pub Bytes);
+
+	impl Credentials for Token {
+		fn mechanism(&self) -> &str {
+			MECHANISM
+		}
+
+		fn respond(&mut self, _challenge: &[u8]) -> Result<Bytes, io::Error> {
+			Ok(self.0.clone())
+		}
+	}
+}
+
+/// Shared secret authentication, the client proves it knows the secret by
+/// answering a random challenge with its HMAC-SHA256.
+#[cfg(feature = "shared-secret")]
+pub mod secret {
+	use std::io;
+	use bytes::{BufMut, Bytes, BytesMut};
+	use hmac::{Hmac, Mac};
+	use sha2::Sha256;
+	use rand::RngCore;
+	use super::{Authenticator, Credentials, Identity, Verdict};
+
+	/// The name of the mechanism.
+	pub const MECHANISM: &str = "HMAC-SHA256";
+
+	fn mac(secret: &[u8]) -> Result<Hmac<Sha256>, io::Error> {
+		Hmac::new_varkey(secret)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid secret"))
+	}
+
+	/// Accept clients knowing the secret associated with their name.
+	pub struct Verifier<L> {
+		lookup: L,
+		nonce: [u8; 32],
+	}
+
+	impl<L> Verifier<L>
+		where L: FnMut(&str) -> Option<Vec<u8>> + Send
+	{
+		/// Create a verifier, looking up the secret for a name.
+		pub fn new(lookup: L) -> Self {
+			Self { lookup, nonce: [0; 32] }
+		}
+	}
+
+	impl<L> Authenticator for Verifier<L>
+		where L: FnMut(&str) -> Option<Vec<u8>> + Send
+	{
+		fn mechanism(&self) -> &str {
+			MECHANISM
+		}
+
+		fn challenge(&mut self) -> Result<Bytes, io::Error> {
+			rand::thread_rng().fill_bytes(&mut self.nonce);
+			Ok(Bytes::from(&self.nonce[..]))
+		}
+
+		fn verify(&mut self, response: &[u8]) -> Result<Verdict, io::Error> {
+			if response.is_empty() || response.len() < 1 + usize::from(response[0]) {
+				return Err(super::rejected());
+			}
+
+			let length = usize::from(response[0]);
+			let name = std::str::from_utf8(&response[1 ..= length])
+				.map_err(|_| super::rejected())?;
+
+			let secret = (self.lookup)(name).ok_or_else(super::rejected)?;
+			let mut mac = mac(&secret)?;
+			mac.input(&self.nonce);
+			mac.verify(&response[1 + length ..]).map_err(|_| super::rejected())?;
+
+			Ok(Verdict::Accept(Identity {
+				mechanism: MECHANISM.into(),
+				name: name.into(),
+			}))
+		}
+	}
+
+	/// Present a name and its secret.
+	pub struct Secret {
+		/// The name to authenticate as.
+		pub name: String,
+
+		/// The secret shared with the server.
+		pub secret: Vec<u8>,
+	}
+
+	impl Credentials for Secret {
+		fn mechanism(&self) -> &str {
+			MECHANISM
+		}
+
+		fn respond(&mut self, challenge: &[u8]) -> Result<Bytes, io::Error> {
+			if self.name.len() > 0xff {
+				return Err(io::Error::new(io::ErrorKind::InvalidInput, "name too long"));
+			}
+
+			let mut mac = mac(&self.secret)?;
+			mac.input(challenge);
+			let code = mac.result().code();
+
+			let mut response = BytesMut::with_capacity(1 + self.name.len() + code.len());
+			response.put_u8(self.name.len() as u8);
+			response.put_slice(self.name.as_bytes());
+			response.put_slice(&code);
+
+			Ok(response.freeze())
+		}
+	}
+}
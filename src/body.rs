@@ -0,0 +1,101 @@
+use std::{io, fmt, mem, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}};
+use bytes::{Bytes, BytesMut};
+use futures::{future, stream::{self, Stream, StreamExt}};
+use tokio::{codec::{FramedRead, BytesCodec}, io::AsyncRead};
+
+type Chunks = Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>;
+
+struct Inner {
+	chunks: Chunks,
+	current: Bytes,
+}
+
+/// A payload that is sent or received lazily, one chunk at a time.
+///
+/// Clones share the same underlying stream, so every chunk is only yielded
+/// once.
+#[derive(Clone)]
+pub struct Body {
+	inner: Arc<Mutex<Inner>>,
+}
+
+impl fmt::Debug for Body {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "Body {{ .. }}")
+	}
+}
+
+impl Body {
+	/// Create a body from a stream of chunks.
+	pub fn new(chunks: impl Stream<Item = Result<Bytes, io::Error>> + Send + 'static) -> Self {
+		Self {
+			inner: Arc::new(Mutex::new(Inner {
+				chunks: Box::pin(chunks),
+				current: Bytes::new(),
+			}))
+		}
+	}
+
+	/// Create a body yielding the prefix before the rest of the body.
+	pub(crate) fn prefixed(prefix: Bytes, body: Body) -> Self {
+		Self::new(stream::once(future::ready(Ok(prefix))).chain(body))
+	}
+
+	/// Create a body skipping the first bytes of the given body.
+	pub(crate) fn skip(mut length: usize, body: Body) -> Self {
+		Self::new(body.map(move |chunk| chunk.map(|mut chunk| {
+			let skipped = length.min(chunk.len());
+			chunk.advance(skipped);
+			length -= skipped;
+
+			chunk
+		})))
+	}
+
+	/// Create a body reading from an `AsyncRead` until EOF.
+	pub fn from_reader(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+		Self::new(FramedRead::new(reader, BytesCodec::new()).map(|chunk| chunk.map(BytesMut::freeze)))
+	}
+}
+
+impl Stream for Body {
+	type Item = Result<Bytes, io::Error>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let mut inner = self.inner.lock().unwrap();
+
+		if !inner.current.is_empty() {
+			return Poll::Ready(Some(Ok(mem::replace(&mut inner.current, Bytes::new()))));
+		}
+
+		inner.chunks.as_mut().poll_next(cx)
+	}
+}
+
+impl AsyncRead for Body {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+		let mut inner = self.inner.lock().unwrap();
+
+		while inner.current.is_empty() {
+			match inner.chunks.as_mut().poll_next(cx) {
+				Poll::Ready(Some(Ok(chunk))) =>
+					inner.current = chunk,
+
+				Poll::Ready(Some(Err(error))) =>
+					return Poll::Ready(Err(error)),
+
+				Poll::Ready(None) =>
+					return Poll::Ready(Ok(0)),
+
+				Poll::Pending =>
+					return Poll::Pending,
+			}
+		}
+
+		let length = buf.len().min(inner.current.len());
+		buf[.. length].copy_from_slice(&inner.current[.. length]);
+		inner.current.advance(length);
+
+		Poll::Ready(Ok(length))
+	}
+}
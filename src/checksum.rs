@@ -0,0 +1,200 @@
+//! Integrity checksums for every fragment.
+//!
+//! Each header is followed by its own checksum, so a corrupted length is
+//! caught before waiting on it, and each payload is followed by another one.
+//! Peers agree on the algorithm with `negotiate` before any fragment is
+//! exchanged.
+//!
+//! Only available with the `crc32c` or `xxhash` features.
+
+use std::{io, fmt, error};
+use tokio::{codec::{Framed, Decoder, Encoder}, io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt}};
+use bytes::{BufMut, Bytes, BytesMut, ByteOrder, BigEndian};
+use crate::{Format, Reframed, Packets, Sessions, packet};
+
+/// A checksum algorithm.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+	/// CRC32 with the Castagnoli polynomial.
+	#[cfg(feature = "crc32c")]
+	Crc32c,
+
+	/// 32-bit xxHash.
+	#[cfg(feature = "xxhash")]
+	XxHash,
+}
+
+impl Algorithm {
+	/// Every algorithm compiled in, in order of preference.
+	pub const ALL: &'static [Algorithm] = &[
+		#[cfg(feature = "crc32c")]
+		Algorithm::Crc32c,
+
+		#[cfg(feature = "xxhash")]
+		Algorithm::XxHash,
+	];
+
+	fn bit(self) -> u8 {
+		match self {
+			#[cfg(feature = "crc32c")]
+			Algorithm::Crc32c => 1 << 0,
+
+			#[cfg(feature = "xxhash")]
+			Algorithm::XxHash => 1 << 1,
+		}
+	}
+
+	/// Compute the checksum of a buffer.
+	pub fn checksum(self, buffer: &[u8]) -> u32 {
+		match self {
+			#[cfg(feature = "crc32c")]
+			Algorithm::Crc32c =>
+				crc32c::crc32c(buffer),
+
+			#[cfg(feature = "xxhash")]
+			Algorithm::XxHash => {
+				use std::hash::Hasher;
+
+				let mut hasher = xxhash::XxHash32::with_seed(0);
+				hasher.write(buffer);
+				hasher.finish() as u32
+			}
+		}
+	}
+}
+
+/// A fragment failed its integrity check.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Corrupted {
+	/// The checksum sent along the fragment.
+	pub expected: u32,
+
+	/// The checksum of the fragment as received.
+	pub actual: u32,
+}
+
+impl fmt::Display for Corrupted {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "corrupted fragment (expected checksum {:08x}, got {:08x})", self.expected, self.actual)
+	}
+}
+
+impl error::Error for Corrupted { }
+
+impl Corrupted {
+	/// Check if an error is caused by a corrupted fragment.
+	pub fn find(error: &io::Error) -> Option<&Corrupted> {
+		error.get_ref().and_then(|error| error.downcast_ref::<Corrupted>())
+	}
+}
+
+/// `tokio::{Decoder, Encoder}` like `Codec`, with a checksum following each
+/// fragment.
+pub struct Codec {
+	algorithm: Algorithm,
+}
+
+impl Codec {
+	/// Create a codec using the given algorithm.
+	pub fn new(algorithm: Algorithm) -> Self {
+		Self { algorithm }
+	}
+
+	/// Check the trailing checksum of the buffer.
+	fn verify(&self, buffer: &[u8]) -> Result<(), io::Error> {
+		let length = buffer.len() - 4;
+		let expected = BigEndian::read_u32(&buffer[length ..]);
+		let actual = self.algorithm.checksum(&buffer[.. length]);
+
+		if expected != actual {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, Corrupted { expected, actual }));
+		}
+
+		Ok(())
+	}
+}
+
+impl Decoder for Codec {
+	type Item = (packet::Header, Bytes);
+	type Error = io::Error;
+
+	fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(packet::Header, Bytes)>, io::Error> {
+		if buf.len() < 4 + 4 {
+			return Ok(None);
+		}
+
+		// The length can't be trusted until the header is verified.
+		self.verify(&buf[.. 4 + 4])?;
+
+		let header = packet::Header::read(buf);
+		let length = 4 + 4 + header.length() + 4;
+
+		if buf.len() < length {
+			return Ok(None);
+		}
+
+		let mut payload = buf.split_to(length);
+		self.verify(&payload[4 + 4 ..])?;
+
+		payload.truncate(length - 4);
+		payload.advance(4 + 4);
+
+		Ok(Some((header, payload.freeze())))
+	}
+}
+
+impl Encoder for Codec {
+	type Item = (packet::Header, Bytes);
+	type Error = io::Error;
+
+	fn encode(&mut self, (header, payload): (packet::Header, Bytes), buf: &mut BytesMut) -> Result<(), io::Error> {
+		buf.reserve(4 + 4 + payload.len() + 4);
+
+		let start = buf.len();
+		header.put(buf, false)?;
+
+		let checksum = self.algorithm.checksum(&buf[start ..]);
+		buf.put_u32_be(checksum);
+
+		let start = buf.len();
+		buf.put_slice(&payload);
+
+		let checksum = self.algorithm.checksum(&buf[start ..]);
+		buf.put_u32_be(checksum);
+
+		Ok(())
+	}
+}
+
+/// Exchange the supported algorithms with the peer, picking the first one in
+/// order of preference both support.
+pub async fn negotiate<S>(socket: &mut S) -> Result<Option<Algorithm>, io::Error>
+	where S: AsyncRead + AsyncWrite + Unpin
+{
+	let supported = Algorithm::ALL.iter().fold(0, |mask, algorithm| mask | algorithm.bit());
+	socket.write_all(&[supported]).await?;
+	socket.flush().await?;
+
+	let mut theirs = [0];
+	socket.read_exact(&mut theirs).await?;
+
+	Ok(Algorithm::ALL.iter().cloned().find(|algorithm| theirs[0] & algorithm.bit() != 0))
+}
+
+/// Negotiate a checksum algorithm with the peer, then build the session stack
+/// verifying every fragment, falling back to no checksums if there is no
+/// algorithm in common.
+pub async fn mi<F, S>(mut socket: S) -> Result<Reframed<Sessions<F>>, io::Error>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	let packets = match negotiate(&mut socket).await? {
+		Some(algorithm) =>
+			Reframed::<Packets<F>>::new(Framed::new(socket, Codec::new(algorithm))),
+
+		None =>
+			Reframed::<Packets<F>>::new(Framed::new(socket, crate::Codec::default())),
+	};
+
+	Ok(packets.into_sessions())
+}
@@ -1,17 +1,34 @@
-use std::{io, usize, marker::PhantomData};
-use tokio::{self, codec::{Decoder, Encoder}, sync::mpsc::{unbounded_channel}};
-use bytes::{BufMut, Bytes, BytesMut, ByteOrder, BigEndian};
-use futures::{stream::{StreamExt}, sink::{SinkExt}};
-use t1ha::T1haHashMap as HashMap;
-use crate::{Format, reframe::{self, Reframe, Source}, packet::{self, Packet}, Session};
+use std::{io::{self, IoSlice}, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}, collections::VecDeque, marker::PhantomData};
+use tokio::{self, codec::{Decoder, Encoder}, io::AsyncWrite, sync::mpsc::{channel, unbounded_channel, UnboundedReceiver}};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{ready, stream::{Stream, StreamExt}, sink::{Sink, SinkExt}};
+use crate::{Format, Body, Reframed, reframe::{self, Reframe, Source}, packet::{self, Packet}, Session, session::{Opener, Registry, Route, Role}, event::{Event, Direction, Observer}};
 
 /// `tokio::{Decoder, Encoder}` to transform a `Stream + Sink` of bytes to one
 /// of header and payload.
-pub struct Codec;
+pub struct Codec {
+	wide: bool,
+}
 
 impl Default for Codec {
 	fn default() -> Self {
-		Self
+		Self { wide: false }
+	}
+}
+
+impl Codec {
+	/// Create a codec using wide headers, carrying 32-bit cookies.
+	pub fn wide() -> Self {
+		Self { wide: true }
+	}
+
+	fn header_size(&self) -> usize {
+		if self.wide {
+			packet::WIDE_SIZE
+		}
+		else {
+			packet::SIZE
+		}
 	}
 }
 
@@ -20,20 +37,25 @@ impl Decoder for Codec {
 	type Error = io::Error;
 
 	fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(packet::Header, Bytes)>, io::Error> {
-		if buf.len() < 4 {
+		let size = self.header_size();
+
+		if buf.len() < size {
 			return Ok(None);
 		}
 
-		let header = packet::Header {
-			cookie: BigEndian::read_u16(&buf[0..]),
-			length: BigEndian::read_u16(&buf[2..])
+		let header = if self.wide {
+			packet::Header::read_wide(buf)
+		}
+		else {
+			packet::Header::read(buf)
 		};
 
-		if buf.len() - 4 < usize::from(header.length()) {
+		if buf.len() - size < header.length() {
 			return Ok(None);
 		}
 
-		let payload = buf.split_off(4);
+		let mut payload = buf.split_to(size + header.length());
+		payload.advance(size);
 
 		Ok(Some((header, payload.freeze())))
 	}
@@ -44,16 +66,176 @@ impl Encoder for Codec {
 	type Error = io::Error;
 
 	fn encode(&mut self, (header, payload): (packet::Header, Bytes), buf: &mut BytesMut) -> Result<(), io::Error> {
-		buf.reserve(4 + payload.len());
-
-		buf.put_u16_be(header.cookie);
-		buf.put_u16_be(header.length);
+		buf.reserve(self.header_size() + payload.len());
+		header.put(buf, self.wide)?;
 		buf.put_slice(&payload);
 
 		Ok(())
 	}
 }
 
+/// Maximum number of buffers handed to a single vectored write.
+const MAX_SLICES: usize = 64;
+
+/// A `Sink` of header and payload that writes to an `AsyncWrite` using
+/// vectored writes, so payloads are never copied into an intermediate buffer.
+pub struct Vectored<W> {
+	writer: W,
+	queue: VecDeque<Bytes>,
+}
+
+impl<W: AsyncWrite + Unpin> Vectored<W> {
+	/// Wrap an `AsyncWrite`.
+	pub fn new(writer: W) -> Self {
+		Self {
+			writer: writer,
+			queue: VecDeque::new(),
+		}
+	}
+
+	/// Consume the `Vectored`, returning the underlying writer.
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+
+	fn poll_write_queue(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		while !self.queue.is_empty() {
+			let mut slices = [IoSlice::new(&[]); MAX_SLICES];
+			let mut count = 0;
+
+			for (slice, bytes) in slices.iter_mut().zip(self.queue.iter()) {
+				*slice = IoSlice::new(bytes);
+				count += 1;
+			}
+
+			let mut written = ready!(Pin::new(&mut self.writer).poll_write_vectored(cx, &slices[.. count]))?;
+
+			if written == 0 {
+				return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+			}
+
+			while written > 0 {
+				let front = self.queue.front_mut().unwrap();
+
+				if front.len() <= written {
+					written -= front.len();
+					self.queue.pop_front();
+				}
+				else {
+					front.advance(written);
+					written = 0;
+				}
+			}
+		}
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<W: AsyncWrite + Unpin> Sink<(packet::Header, Bytes)> for Vectored<W> {
+	type Error = io::Error;
+
+	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.get_mut();
+
+		if this.queue.len() >= MAX_SLICES {
+			ready!(this.poll_write_queue(cx))?;
+		}
+
+		Poll::Ready(Ok(()))
+	}
+
+	fn start_send(self: Pin<&mut Self>, (header, payload): (packet::Header, Bytes)) -> Result<(), Self::Error> {
+		let this = self.get_mut();
+
+		let mut bytes = BytesMut::with_capacity(packet::SIZE);
+		header.put(&mut bytes, false)?;
+
+		this.queue.push_back(bytes.freeze());
+
+		if !payload.is_empty() {
+			this.queue.push_back(payload);
+		}
+
+		Ok(())
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.get_mut();
+
+		ready!(this.poll_write_queue(cx))?;
+		Pin::new(&mut this.writer).poll_flush(cx)
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.get_mut();
+
+		ready!(this.poll_write_queue(cx))?;
+		Pin::new(&mut this.writer).poll_shutdown(cx)
+	}
+}
+
+/// Fragment outgoing packets into header and payload.
+fn fragment<F: Format>(mut sink: Pin<Box<dyn Sink<(packet::Header, Bytes), Error = io::Error> + Send>>) -> impl Sink<Packet<F>, Error = io::Error> {
+	reframe::sink(|mut rx| async move {
+		// Skipping a fragment the transport refused would corrupt the packet, so
+		// the connection is aborted instead.
+		macro_rules! send {
+			($fragment:expr) => (
+				if sink.send($fragment).await.is_err() {
+					sink.close().await.ok();
+					return;
+				}
+			);
+		}
+
+		while let Some(packet) = rx.next().await : Option<Packet<F>> {
+			let Packet { cookie, bytes, body, .. } = packet;
+
+			if let Some(mut body) = body {
+				let mut pending = BytesMut::new();
+
+				while let Some(chunk) = body.next().await {
+					let chunk = match chunk {
+						Ok(chunk) =>
+							chunk,
+
+						// The peer would take a truncated payload as complete, so the
+						// connection is aborted instead of ending the packet.
+						Err(_) => {
+							sink.close().await.ok();
+							return;
+						}
+					};
+
+					pending.extend_from_slice(&chunk);
+
+					while pending.len() >= 0xfffe {
+						let payload = pending.split_to(0xfffe).freeze();
+						send!((packet::Header::new(cookie, None), payload));
+					}
+				}
+
+				let payload = pending.freeze();
+				send!((packet::Header::new(cookie, Some(payload.len())), payload));
+
+				continue;
+			}
+
+			let mut chunks = (0 ..= bytes.len() / 0xfffe).peekable();
+
+			while let Some(chunk) = chunks.next() {
+				let is_last = chunks.peek().is_none();
+				let payload = bytes.slice(chunk * 0xfffe, bytes.len().min((chunk + 1) * 0xfffe));
+				let length  = if is_last { Some(payload.len()) } else { None };
+				let header  = packet::Header::new(cookie, length);
+
+				send!((header, payload));
+			}
+		}
+	}).sink_map_err(|err| io::Error::new(io::ErrorKind::Interrupted, err))
+}
+
 /// Reframe a `Codec` into a `Packet`.
 #[derive(Copy, Clone, Debug)]
 pub struct Packets<F = ()> {
@@ -68,11 +250,14 @@ impl<F: Format> Reframe for Packets<F> {
 	type SinkInto = Packet<F>;
 
 	type Error = io::Error;
+	type Handle = Observer;
 
-	fn reframe(source: Source<Self::StreamFrom, Self::SinkFrom, Self::Error>) -> Source<Self::StreamInto, Self::SinkInto, Self::Error> {
-		let Source { mut stream, mut sink } = source;
+	fn reframe(source: Source<Self::StreamFrom, Self::SinkFrom, Self::Error>) -> (Source<Self::StreamInto, Self::SinkInto, Self::Error>, Self::Handle) {
+		let Source { mut stream, sink } = source;
+		let observer = Observer::default();
+		let events = observer.clone();
 
-		Source::new(
+		(Source::new(
 			reframe::stream(|mut out| async move {
 				macro_rules! next {
 					($body:expr) => (
@@ -98,48 +283,105 @@ impl<F: Format> Reframe for Packets<F> {
 					let mut packet = next!(stream);
 					payload.extend_from_slice(&packet.1);
 
-					while packet.0.has_more_payload() {
-						packet = next!(stream);
-						payload.extend_from_slice(&packet.1);
+					if packet.0.has_more_payload() {
+						let cookie = packet.0.cookie();
+						let mut fragments = 1;
+						events.emit(Event::ReassemblyStarted { cookie });
+
+						while packet.0.has_more_payload() {
+							packet = next!(stream);
+							payload.extend_from_slice(&packet.1);
+							fragments += 1;
+						}
+
+						events.emit(Event::ReassemblyCompleted { cookie, fragments, length: payload.len() });
 					}
 
-					out.send(Ok(if let Some(cookie) = packet.0.cookie() {
-						if packet.0.has_more_packets() {
-							Packet::<F>::new(packet::Cookie::Stream(cookie), payload.freeze())
+					out.send(Ok(Packet::<F>::new(packet.0.to_cookie(), payload.freeze()))).await.unwrap();
+				}
+			}),
+
+			fragment(sink)), observer)
+	}
+}
+
+/// Reframe a `Codec` into a `Packet`, payloads spanning multiple fragments
+/// are not reassembled but exposed as a `Body` yielding fragments as they
+/// arrive.
+///
+/// Only a few fragments are buffered for each `Body`, so one that is never
+/// drained stalls every session on the connection.
+#[derive(Copy, Clone, Debug)]
+pub struct Streaming<F = ()> {
+	_marker: PhantomData<F>
+}
+
+impl<F: Format> Reframe for Streaming<F> {
+	type StreamFrom = (packet::Header, Bytes);
+	type StreamInto = Packet<F>;
+
+	type SinkFrom = (packet::Header, Bytes);
+	type SinkInto = Packet<F>;
+
+	type Error = io::Error;
+	type Handle = Observer;
+
+	fn reframe(source: Source<Self::StreamFrom, Self::SinkFrom, Self::Error>) -> (Source<Self::StreamInto, Self::SinkInto, Self::Error>, Self::Handle) {
+		let Source { mut stream, sink } = source;
+		let observer = Observer::default();
+		let events = observer.clone();
+
+		(Source::new(
+			reframe::stream(|mut out| async move {
+				macro_rules! next {
+					($body:expr) => (
+						if let Some(value) = stream.next().await {
+							match value {
+								Ok(value) => value,
+
+								Err(error) => {
+									out.send(Err(error)).await.unwrap();
+									return;
+								}
+							}
 						}
 						else {
-							Packet::<F>::new(packet::Cookie::Single(cookie), payload.freeze())
+							return;
 						}
-					}
-					else {
-						Packet::<F>::new(packet::Cookie::Oneshot, payload.freeze())
-					})).await.unwrap();
+					);
 				}
-			}),
 
-			reframe::sink(|mut rx| async move {
-				while let Some(packet) = rx.next().await : Option<Packet<F>> {
-					let mut chunks = (0 ..= packet.bytes().len() / 0xfffe).peekable();
+				loop {
+					let mut packet = next!(stream);
+
+					if !packet.0.has_more_payload() {
+						out.send(Ok(Packet::<F>::new(packet.0.to_cookie(), packet.1))).await.unwrap();
+						continue;
+					}
 
-					while let Some(chunk) = chunks.next() {
-						let is_last = chunks.peek().is_none();
-						let payload = packet.bytes().slice(chunk * 0xfffe, packet.bytes().len() - (chunk * 0xfffe));
-						let length  = if is_last { Some(payload.len()) } else { None };
-						let header  = match packet.cookie() {
-							packet::Cookie::Oneshot =>
-								packet::Header::oneshot(length),
+					let cookie = packet.0.cookie();
+					let mut fragments = 1;
+					let mut length = packet.1.len();
+					events.emit(Event::ReassemblyStarted { cookie });
 
-							packet::Cookie::Single(cookie) =>
-								packet::Header::single(cookie, length),
+					let (mut chunks, rx) = channel(16);
+					out.send(Ok(Packet::<F>::streamed(packet.0.to_cookie(), Body::new(rx.map(Ok))))).await.unwrap();
 
-							packet::Cookie::Stream(cookie) =>
-								packet::Header::stream(cookie, length),
-						};
+					// The body may have been dropped, keep draining the fragments anyway.
+					chunks.send(packet.1).await.ok();
 
-						sink.send((header, payload)).await.unwrap();
+					while packet.0.has_more_payload() {
+						packet = next!(stream);
+						fragments += 1;
+						length += packet.1.len();
+						chunks.send(packet.1).await.ok();
 					}
+
+					events.emit(Event::ReassemblyCompleted { cookie, fragments, length });
 				}
-			}).sink_map_err(|err| io::Error::new(io::ErrorKind::Interrupted, err)))
+			}),
+
+			fragment(sink)), observer)
 	}
 }
 
@@ -151,6 +393,7 @@ pub struct Sessions<F = ()> {
 
 impl<F: Format> Reframe for Sessions<F> {
 	type Error = io::Error;
+	type Handle = Opener<F>;
 
 	type StreamFrom = Packet<F>;
 	type StreamInto = Session<F>;
@@ -158,81 +401,212 @@ impl<F: Format> Reframe for Sessions<F> {
 	type SinkFrom = Packet<F>;
 	type SinkInto = Packet<F>;
 
-	fn reframe(source: Source<Self::StreamFrom, Self::SinkFrom, Self::Error>) -> Source<Self::StreamInto, Self::SinkInto, Self::Error> {
-		let reframe::Source { mut stream, mut sink } = source;
-		let sink = {
-			let (tx, mut rx) = unbounded_channel();
+	fn reframe(source: Source<Self::StreamFrom, Self::SinkFrom, Self::Error>) -> (Source<Self::StreamInto, Self::SinkInto, Self::Error>, Self::Handle) {
+		sessions(source, None)
+	}
+}
 
-			tokio::spawn(async move {
-				while let Some(value) = rx.next().await : Option<Packet<F>> {
-					sink.send(value).await.unwrap();
+/// Reframe packets into sessions, enforcing cookie ownership if the end of
+/// the connection is known.
+fn sessions<F: Format>(source: Source<Packet<F>, Packet<F>, io::Error>, role: Option<Role>) -> (Source<Session<F>, Packet<F>, io::Error>, Opener<F>) {
+	let reframe::Source { mut stream, mut sink } = source;
+	let observer = Observer::default();
+	let events = observer.clone();
+
+	let registry = Arc::new(Mutex::new(Registry::new(role)));
+
+	let sink = {
+		let (tx, mut rx) = unbounded_channel();
+		let events = observer.clone();
+		let registry = registry.clone();
+
+		tokio::spawn(async move {
+			while let Some(value) = rx.next().await : Option<Packet<F>> {
+				if let packet::Cookie::Single(cookie) = value.cookie() {
+					events.emit(Event::Ended { cookie, direction: Direction::Outgoing });
+					registry.lock().unwrap().end_outgoing(cookie);
 				}
-			});
 
-			tx
-		};
-
-		let sunk = sink.clone();
-
-		reframe::Source::new(
-			reframe::stream(|mut out| async move {
-				macro_rules! next {
-					($body:expr) => (
-						if let Some(value) = stream.next().await {
-							match value {
-								Ok(value) => value,
-
-								Err(error) => {
-									out.send(Err(error)).await.unwrap();
-									return;
-								}
+				if sink.send(value).await.is_err() {
+					break;
+				}
+			}
+		});
+
+		tx
+	};
+
+	let sunk = sink.clone();
+	let opener = Opener::new(registry.clone(), sink.clone(), observer);
+	let sessions = opener.clone();
+
+	(reframe::Source::new(
+		reframe::stream(|mut out| async move {
+			macro_rules! next {
+				($body:expr) => (
+					if let Some(value) = stream.next().await {
+						match value {
+							Ok(value) => value,
+
+							Err(error) => {
+								events.emit(Event::error(&error));
+								out.send(Err(error)).await.unwrap();
+								return;
 							}
 						}
-						else {
-							return;
-						}
-					);
-				}
+					}
+					else {
+						return;
+					}
+				);
+			}
 
-				let mut channels = HashMap::<u16, _>::default();
+			events.emit(Event::Opened);
 
+			async {
 				loop {
 					let packet = next!(stream);
 
 					match packet.cookie() {
 						packet::Cookie::Oneshot => {
-							out.send(Ok(Session::no_reply(packet))).await.unwrap();
+							out.send(Ok(Session::no_reply(packet).with_opener(sessions.clone()))).await.unwrap();
 						}
 
 						packet::Cookie::Stream(cookie) => {
-							if !channels.contains_key(&cookie) {
-								let session = Session::new(cookie, sink.clone());
-								let sender = session.sender();
+							let (route, session) = {
+								let mut registry = registry.lock().unwrap();
+								let route = registry.route(cookie, false);
 
-								out.send(Ok(session)).await.unwrap();
-								channels.insert(cookie, sender);
-							}
+								if let Route::New = route {
+									let session = sessions.start(&mut registry, cookie);
+									registry.receive(cookie);
+
+									(route, Some(session))
+								}
+								else {
+									(route, None)
+								}
+							};
+
+							match (route, session) {
+								(Route::New, Some(session)) => {
+									let mut sender = session.sender();
+
+									events.emit(Event::Created { cookie, direction: Direction::Incoming });
+									out.send(Ok(session)).await.unwrap();
 
-							channels.get_mut(&cookie).unwrap().send(packet.into()).await.unwrap();
+									// The session may have been dropped already.
+									sender.send(packet.into()).await.ok();
+								}
+
+								(Route::Session(mut sender), _) => {
+									sender.send(packet.into()).await.ok();
+								}
+
+								(Route::Dropped, _) =>
+									events.emit(Event::Dropped { cookie }),
+
+								_ =>
+									(),
+							}
 						}
 
 						packet::Cookie::Single(cookie) => {
-							if channels.contains_key(&cookie) {
-								channels.get_mut(&cookie).unwrap().send(packet.into()).await.unwrap();
-								channels.remove(&cookie);
-							}
-							else {
-								let session = Session::new(cookie, sink.clone());
-								let mut sender = session.sender();
+							let route = registry.lock().unwrap().route(cookie, true);
+
+							match route {
+								Route::Session(mut sender) => {
+									events.emit(Event::Ended { cookie, direction: Direction::Incoming });
+									sender.send(packet.into()).await.ok();
+								}
 
-								out.send(Ok(session)).await.unwrap();
-								sender.send(packet.into()).await.unwrap();
+								Route::New => {
+									let session = Session::new(cookie, sink.clone()).with_opener(sessions.clone());
+									let mut sender = session.sender();
+
+									events.emit(Event::Created { cookie, direction: Direction::Incoming });
+									events.emit(Event::Ended { cookie, direction: Direction::Incoming });
+
+									out.send(Ok(session)).await.unwrap();
+									sender.send(packet.into()).await.ok();
+								}
+
+								Route::Dropped =>
+									events.emit(Event::Dropped { cookie }),
+
+								Route::Reset =>
+									(),
 							}
 						}
 					}
 				}
-			}),
+			}.await;
+
+			// Whatever is still open can never end now.
+			let open = registry.lock().unwrap().clear();
+			for cookie in open {
+				events.emit(Event::Reset { cookie });
+			}
+
+			events.emit(Event::Closed);
+		}),
+
+		sunk.sink_map_err(|err| io::Error::new(io::ErrorKind::Interrupted, err))), opener)
+}
+
+impl<F: Format> Reframed<Sessions<F>> {
+	/// Open a session toward the peer on an unused cookie, same as
+	/// `opener().open()` without cloning the `Opener`.
+	pub fn open(&self) -> Result<Session<F>, io::Error> {
+		self.handle().open()
+	}
+
+	/// Build sessions over the packets, enforcing cookie ownership for the
+	/// given end of the connection if any.
+	pub(crate) fn from_parts_as(stream: impl Stream<Item = Result<Packet<F>, io::Error>> + Send + 'static, sink: impl Sink<Packet<F>, Error = io::Error> + Send + 'static, role: Option<Role>) -> Self {
+		Self::from_parts_with(stream, sink, |source| sessions(source, role))
+	}
+
+	/// An opener for sessions toward the peer, which can be cloned and moved
+	/// elsewhere, so sessions can still be opened once the connection itself
+	/// is split or moved into the task yielding the sessions the peer starts.
+	pub fn opener(&self) -> Opener<F> {
+		self.handle().clone()
+	}
+
+	/// Subscribe to the lifecycle events of the connection.
+	pub fn events(&self) -> UnboundedReceiver<Event> {
+		self.handle().events()
+	}
+}
+
+impl<F, R> Reframed<R>
+	where F: Format,
+	      R: Reframe<StreamInto = Packet<F>, SinkInto = Packet<F>, Error = io::Error, Handle = Observer>
+{
+	/// Subscribe to the reassembly events of the packets.
+	pub fn events(&self) -> UnboundedReceiver<Event> {
+		self.handle().subscribe()
+	}
+
+	/// Build sessions on top of the packets, so the subscribers to the events
+	/// of the sessions see the reassembly events as well.
+	pub fn into_sessions(self) -> Reframed<Sessions<F>> {
+		self.into_sessions_with(None)
+	}
+
+	/// Like `into_sessions`, enforcing cookie ownership for the given end of
+	/// the connection, the peer must be using the opposite role.
+	pub fn into_sessions_as(self, role: Role) -> Reframed<Sessions<F>> {
+		self.into_sessions_with(Some(role))
+	}
+
+	pub(crate) fn into_sessions_with(self, role: Option<Role>) -> Reframed<Sessions<F>> {
+		let observer = self.handle().clone();
+		let (sink, stream) = self.split();
+		let sessions = Reframed::<Sessions<F>>::from_parts_as(stream, sink, role);
+		observer.forward(sessions.handle().observer().clone());
 
-			sunk.sink_map_err(|err| io::Error::new(io::ErrorKind::Interrupted, err)))
+		sessions
 	}
 }
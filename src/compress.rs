@@ -0,0 +1,352 @@
+//! Per-message payload compression.
+//!
+//! Every payload is prefixed with a byte identifying the `Algorithm` it was
+//! compressed with, the first packet each peer sends is an offer of the
+//! algorithms it supports, and each peer then compresses with the first
+//! algorithm in its own preference the other peer offered.
+//!
+//! With the `zstd` feature, `Context` instead compresses the whole connection
+//! byte stream, so repeated message shapes share a single compression context.
+
+use std::{io, sync::{Arc, atomic::{AtomicU8, Ordering}}, marker::PhantomData};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{stream::StreamExt, sink::SinkExt};
+use crate::{Format, Body, reframe::{self, Reframe, Source}, packet::{self, Packet}};
+
+#[cfg(feature = "zstd")]
+mod context;
+#[cfg(feature = "zstd")]
+pub use self::context::{Context, Options, train};
+
+/// Marker for the offer packet.
+const OFFER: u8 = 0xff;
+
+/// A compression algorithm.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+	/// The payload is not compressed.
+	None,
+
+	/// Zstandard.
+	#[cfg(feature = "zstd")]
+	Zstd,
+
+	/// LZ4 block format.
+	#[cfg(feature = "lz4")]
+	Lz4,
+
+	/// Raw DEFLATE.
+	#[cfg(feature = "deflate")]
+	Deflate,
+}
+
+impl Algorithm {
+	/// Every algorithm compiled in, in order of preference.
+	pub const ALL: &'static [Algorithm] = &[
+		#[cfg(feature = "zstd")]
+		Algorithm::Zstd,
+
+		#[cfg(feature = "lz4")]
+		Algorithm::Lz4,
+
+		#[cfg(feature = "deflate")]
+		Algorithm::Deflate,
+	];
+
+	/// The identifier of the algorithm on the wire.
+	pub fn id(self) -> u8 {
+		match self {
+			Algorithm::None => 0,
+
+			#[cfg(feature = "zstd")]
+			Algorithm::Zstd => 1,
+
+			#[cfg(feature = "lz4")]
+			Algorithm::Lz4 => 2,
+
+			#[cfg(feature = "deflate")]
+			Algorithm::Deflate => 3,
+		}
+	}
+
+	/// Get the algorithm from its identifier, if supported.
+	pub fn from_id(id: u8) -> Option<Self> {
+		match id {
+			0 => Some(Algorithm::None),
+
+			#[cfg(feature = "zstd")]
+			1 => Some(Algorithm::Zstd),
+
+			#[cfg(feature = "lz4")]
+			2 => Some(Algorithm::Lz4),
+
+			#[cfg(feature = "deflate")]
+			3 => Some(Algorithm::Deflate),
+
+			_ => None,
+		}
+	}
+
+	/// Compress the input, appending to the output.
+	pub fn compress(self, input: &[u8], output: &mut BytesMut) -> Result<(), io::Error> {
+		match self {
+			Algorithm::None => {
+				output.extend_from_slice(input);
+			}
+
+			#[cfg(feature = "zstd")]
+			Algorithm::Zstd => {
+				output.extend_from_slice(&zstd::stream::encode_all(input, 0)?);
+			}
+
+			#[cfg(feature = "lz4")]
+			Algorithm::Lz4 => {
+				output.extend_from_slice(&lz4::block::compress(input, None, true)?);
+			}
+
+			#[cfg(feature = "deflate")]
+			Algorithm::Deflate => {
+				use std::io::Write;
+
+				let mut encoder = flate2::write::DeflateEncoder::new(output.writer(), flate2::Compression::default());
+				encoder.write_all(input)?;
+				encoder.finish()?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Decompress the input, appending to the output, failing with
+	/// `InvalidData` if it would be longer than `limit`.
+	pub fn decompress(self, input: &[u8], output: &mut BytesMut, limit: usize) -> Result<(), io::Error> {
+		match self {
+			Algorithm::None => {
+				if input.len() > limit {
+					return Err(too_large());
+				}
+
+				output.extend_from_slice(input);
+			}
+
+			#[cfg(feature = "zstd")]
+			Algorithm::Zstd => {
+				read_limited(zstd::stream::read::Decoder::new(input)?, output, limit)?;
+			}
+
+			#[cfg(feature = "lz4")]
+			Algorithm::Lz4 => {
+				use bytes::{ByteOrder, LittleEndian};
+
+				// The decompressed size is prepended, so it can be checked before
+				// allocating anything.
+				if input.len() < 4 || LittleEndian::read_u32(input) as usize > limit {
+					return Err(too_large());
+				}
+
+				output.extend_from_slice(&lz4::block::decompress(input, None)?);
+			}
+
+			#[cfg(feature = "deflate")]
+			Algorithm::Deflate => {
+				read_limited(flate2::read::DeflateDecoder::new(input), output, limit)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+fn too_large() -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, "decompressed payload too large")
+}
+
+/// Read everything from a decompressor, failing once more than `limit` bytes
+/// come out of it.
+#[cfg(any(feature = "zstd", feature = "deflate"))]
+fn read_limited(reader: impl io::Read, output: &mut BytesMut, limit: usize) -> Result<(), io::Error> {
+	use std::io::Read;
+
+	let read = io::copy(&mut reader.take(limit as u64 + 1), &mut output.writer())?;
+
+	if read > limit as u64 {
+		return Err(too_large());
+	}
+
+	Ok(())
+}
+
+/// Compression settings for a connection.
+pub trait Config: Send + Sync + 'static {
+	/// Payloads smaller than this are sent uncompressed.
+	const THRESHOLD: usize;
+
+	/// Payloads decompressing to more than this are rejected.
+	const LIMIT: usize = 16 * 1024 * 1024;
+
+	/// The algorithms to offer, in order of preference.
+	fn algorithms() -> &'static [Algorithm];
+}
+
+/// Offer every algorithm compiled in, compressing payloads of at least 512
+/// bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct Defaults;
+
+impl Config for Defaults {
+	const THRESHOLD: usize = 512;
+
+	fn algorithms() -> &'static [Algorithm] {
+		Algorithm::ALL
+	}
+}
+
+/// Reframe packets compressing and decompressing their payloads.
+///
+/// Both peers must use this stage. Framed packets are copied, and streamed
+/// bodies are never compressed.
+#[derive(Copy, Clone, Debug)]
+pub struct Compressed<F = (), C = Defaults> {
+	_marker: PhantomData<(F, C)>,
+}
+
+impl<F: Format, C: Config> Reframe for Compressed<F, C> {
+	type StreamFrom = Packet<F>;
+	type StreamInto = Packet<F>;
+
+	type SinkFrom = Packet<F>;
+	type SinkInto = Packet<F>;
+
+	type Error = io::Error;
+	type Handle = ();
+
+	fn reframe(source: Source<Self::StreamFrom, Self::SinkFrom, Self::Error>) -> (Source<Self::StreamInto, Self::SinkInto, Self::Error>, Self::Handle) {
+		let Source { mut stream, mut sink } = source;
+
+		// Nothing is compressed until the peer offer has been received.
+		let chosen = Arc::new(AtomicU8::new(Algorithm::None.id()));
+		let negotiated = chosen.clone();
+
+		(Source::new(
+			reframe::stream(|mut out| async move {
+				while let Some(packet) = stream.next().await {
+					let packet = match packet {
+						Ok(packet) => packet,
+
+						Err(error) => {
+							out.send(Err(error)).await.unwrap();
+							return;
+						}
+					};
+
+					match decompress::<F, C>(packet) {
+						Decompressed::Packet(packet) => {
+							out.send(Ok(packet)).await.unwrap();
+						}
+
+						Decompressed::Offer(offer) => {
+							if let Some(algorithm) = C::algorithms().iter().find(|a| offer.contains(&a.id())) {
+								negotiated.store(algorithm.id(), Ordering::SeqCst);
+							}
+						}
+
+						Decompressed::Invalid(error) => {
+							out.send(Err(error)).await.unwrap();
+							return;
+						}
+					}
+				}
+			}),
+
+			reframe::sink(|mut rx| async move {
+				let mut offer = BytesMut::with_capacity(1 + C::algorithms().len());
+				offer.put_u8(OFFER);
+
+				for algorithm in C::algorithms() {
+					offer.put_u8(algorithm.id());
+				}
+
+				if sink.send(Packet::new(packet::Cookie::Oneshot, offer.freeze())).await.is_err() {
+					return;
+				}
+
+				while let Some(packet) = rx.next().await : Option<Packet<F>> {
+					let algorithm = Algorithm::from_id(chosen.load(Ordering::SeqCst)).unwrap();
+
+					if sink.send(compress::<F, C>(packet, algorithm)).await.is_err() {
+						return;
+					}
+				}
+			}).sink_map_err(|err| io::Error::new(io::ErrorKind::Interrupted, err))), ())
+	}
+}
+
+/// The result of decompressing an incoming packet.
+enum Decompressed<F> {
+	/// A packet with its original payload.
+	Packet(Packet<F>),
+
+	/// The peer offer, in its order of preference.
+	Offer(Bytes),
+
+	/// The payload could not be decompressed.
+	Invalid(io::Error),
+}
+
+fn compress<F: Format, C: Config>(mut packet: Packet<F>, algorithm: Algorithm) -> Packet<F> {
+	if let Some(body) = packet.body.take() {
+		return packet.with_body(Body::prefixed(Bytes::from_static(&[0]), body));
+	}
+
+	let algorithm = if packet.bytes.len() < C::THRESHOLD {
+		Algorithm::None
+	}
+	else {
+		algorithm
+	};
+
+	let mut bytes = BytesMut::with_capacity(1 + packet.bytes.len());
+	bytes.put_u8(algorithm.id());
+
+	if algorithm.compress(&packet.bytes, &mut bytes).is_err() {
+		bytes.truncate(0);
+		bytes.put_u8(Algorithm::None.id());
+		bytes.extend_from_slice(&packet.bytes);
+	}
+
+	packet.with_bytes(bytes.freeze())
+}
+
+fn decompress<F: Format, C: Config>(mut packet: Packet<F>) -> Decompressed<F> {
+	if let Some(body) = packet.body.take() {
+		return Decompressed::Packet(packet.with_body(Body::skip(1, body)));
+	}
+
+	if packet.bytes.is_empty() {
+		return Decompressed::Invalid(io::Error::new(io::ErrorKind::InvalidData, "missing compression prefix"));
+	}
+
+	if packet.bytes[0] == OFFER {
+		return Decompressed::Offer(packet.bytes.slice_from(1));
+	}
+
+	match Algorithm::from_id(packet.bytes[0]) {
+		Some(Algorithm::None) => {
+			let bytes = packet.bytes.slice_from(1);
+			Decompressed::Packet(packet.with_bytes(bytes))
+		}
+
+		Some(algorithm) => {
+			let mut bytes = BytesMut::new();
+
+			if let Err(error) = algorithm.decompress(&packet.bytes[1..], &mut bytes, C::LIMIT) {
+				return Decompressed::Invalid(error);
+			}
+
+			Decompressed::Packet(packet.with_bytes(bytes.freeze()))
+		}
+
+		None =>
+			Decompressed::Invalid(io::Error::new(io::ErrorKind::InvalidData, "unknown compression algorithm")),
+	}
+}
@@ -0,0 +1,170 @@
+use std::{io, pin::Pin, task::{self, Poll}};
+use bytes::BytesMut;
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite};
+use zstd::stream::raw::{Encoder, Decoder, Operation, InBuffer, OutBuffer};
+
+/// Size of the scratch buffers used by `Context`.
+const CHUNK: usize = 8 * 1024;
+
+/// Settings for a `Context`.
+#[derive(Clone, Debug)]
+pub struct Options {
+	/// The zstd compression level.
+	pub level: i32,
+
+	/// A dictionary both peers agree on, trained on sample messages.
+	pub dictionary: Option<Vec<u8>>,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self {
+			level: 3,
+			dictionary: None,
+		}
+	}
+}
+
+/// Train a dictionary from sample payloads.
+pub fn train<S: AsRef<[u8]>>(samples: &[S], size: usize) -> Result<Vec<u8>, io::Error> {
+	zstd::dict::from_samples(samples, size)
+}
+
+/// An `AsyncRead + AsyncWrite` compressing the whole byte stream with a single
+/// zstd context, flushed whenever the writer is.
+///
+/// Used by `mi_zstd`, or wrap the socket before handing it to any other `mi`
+/// helper, both peers must use the same `Options`.
+pub struct Context<S> {
+	inner: S,
+
+	encoder: Encoder,
+	decoder: Decoder,
+
+	input: BytesMut,
+	output: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Context<S> {
+	/// Wrap a socket.
+	pub fn new(inner: S, options: Options) -> Result<Self, io::Error> {
+		let (encoder, decoder) = if let Some(dictionary) = &options.dictionary {
+			(Encoder::with_dictionary(options.level, dictionary)?,
+			 Decoder::with_dictionary(dictionary)?)
+		}
+		else {
+			(Encoder::new(options.level)?,
+			 Decoder::new()?)
+		};
+
+		Ok(Self {
+			inner: inner,
+
+			encoder: encoder,
+			decoder: decoder,
+
+			input: BytesMut::new(),
+			output: BytesMut::new(),
+		})
+	}
+
+	/// Consume the `Context`, returning the underlying socket.
+	pub fn into_inner(self) -> S {
+		self.inner
+	}
+
+	fn poll_drain(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), io::Error>> {
+		while !self.output.is_empty() {
+			let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output))?;
+
+			if written == 0 {
+				return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+			}
+
+			self.output.advance(written);
+		}
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Context<S> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+
+		loop {
+			let mut input = InBuffer::around(&this.input);
+			let mut output = OutBuffer::around(buf);
+			this.decoder.run(&mut input, &mut output)?;
+
+			let (consumed, produced) = (input.pos, output.pos);
+			this.input.advance(consumed);
+
+			if produced > 0 {
+				return Poll::Ready(Ok(produced));
+			}
+
+			if consumed > 0 && !this.input.is_empty() {
+				continue;
+			}
+
+			let mut chunk = [0; CHUNK];
+			let read = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+
+			if read == 0 {
+				return Poll::Ready(Ok(0));
+			}
+
+			this.input.extend_from_slice(&chunk[.. read]);
+		}
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Context<S> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+
+		if this.output.len() >= CHUNK {
+			ready!(this.poll_drain(cx))?;
+		}
+
+		let mut input = InBuffer::around(buf);
+
+		while input.pos < buf.len() {
+			let mut chunk = [0; CHUNK];
+			let mut output = OutBuffer::around(&mut chunk);
+			this.encoder.run(&mut input, &mut output)?;
+
+			let produced = output.pos;
+			this.output.extend_from_slice(&chunk[.. produced]);
+		}
+
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<(), io::Error>> {
+		let this = self.get_mut();
+
+		loop {
+			let mut chunk = [0; CHUNK];
+			let mut output = OutBuffer::around(&mut chunk);
+			let remaining = this.encoder.flush(&mut output)?;
+
+			let produced = output.pos;
+			this.output.extend_from_slice(&chunk[.. produced]);
+
+			if remaining == 0 {
+				break;
+			}
+		}
+
+		ready!(this.poll_drain(cx))?;
+		Pin::new(&mut this.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<(), io::Error>> {
+		ready!(self.as_mut().poll_flush(cx))?;
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
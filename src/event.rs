@@ -0,0 +1,154 @@
+//! Lifecycle events of connections and sessions, for dashboards and debugging
+//! tools.
+//!
+//! Subscribers that are dropped are forgotten, so observing is entirely
+//! optional.
+
+use std::{io, sync::{Arc, Mutex}};
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+
+/// Which end started a session, or sent the end of it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Direction {
+	/// The peer.
+	Incoming,
+
+	/// This end.
+	Outgoing,
+}
+
+/// Something that happened on a connection.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+	/// The connection is open, always the first event a subscriber sees while
+	/// it is.
+	Opened,
+
+	/// The connection is closed, no more events will follow.
+	Closed,
+
+	/// A session was started, oneshot packets aren't sessions of their own so
+	/// they have neither this nor `Ended`.
+	Created {
+		cookie: u32,
+		direction: Direction,
+	},
+
+	/// A message with `Mode::End` was seen on a session.
+	Ended {
+		cookie: u32,
+		direction: Direction,
+	},
+
+	/// A session went away without ending, either because it was dropped
+	/// while the peer was still sending, or because the connection closed.
+	Reset {
+		cookie: u32,
+	},
+
+	/// A packet from the peer was dropped, because it came after the peer
+	/// ended the session, or on a cookie the peer doesn't own.
+	Dropped {
+		cookie: u32,
+	},
+
+	/// The first of multiple fragments of a packet arrived, there is no cookie
+	/// for oneshot packets.
+	///
+	/// Reassembly events come from the `Packets` or `Streaming` under the
+	/// sessions, a `quic::Connection` has no events at all.
+	ReassemblyStarted {
+		cookie: Option<u32>,
+	},
+
+	/// The last fragment of a packet arrived.
+	ReassemblyCompleted {
+		cookie: Option<u32>,
+		fragments: usize,
+		length: usize,
+	},
+
+	/// An error was yielded by the connection.
+	Error {
+		kind: io::ErrorKind,
+		message: String,
+	},
+}
+
+impl Event {
+	pub(crate) fn error(error: &io::Error) -> Self {
+		Event::Error {
+			kind: error.kind(),
+			message: error.to_string(),
+		}
+	}
+}
+
+#[derive(Default)]
+struct Inner {
+	subscribers: Vec<UnboundedSender<Event>>,
+	forward: Option<Observer>,
+	open: bool,
+}
+
+/// Dispatches events to any number of subscribers.
+#[derive(Clone, Default)]
+pub struct Observer {
+	inner: Arc<Mutex<Inner>>,
+}
+
+impl Observer {
+	/// Subscribe to the events from now on.
+	pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+		let (mut tx, rx) = unbounded_channel();
+		let mut inner = self.inner.lock().unwrap();
+
+		if inner.open {
+			tx.try_send(Event::Opened).ok();
+		}
+
+		inner.subscribers.push(tx);
+		rx
+	}
+
+	/// Send the event to every subscriber, then to the observer it is
+	/// forwarded to.
+	pub(crate) fn emit(&self, event: Event) {
+		let forward = {
+			let mut inner = self.inner.lock().unwrap();
+
+			match event {
+				Event::Opened =>
+					inner.open = true,
+
+				Event::Closed =>
+					inner.open = false,
+
+				_ =>
+					(),
+			}
+
+			let mut index = 0;
+			while index < inner.subscribers.len() {
+				if inner.subscribers[index].try_send(event.clone()).is_ok() {
+					index += 1;
+				}
+				else {
+					inner.subscribers.swap_remove(index);
+				}
+			}
+
+			inner.forward.clone()
+		};
+
+		if let Some(forward) = forward {
+			forward.emit(event);
+		}
+	}
+
+	/// Send every event to the given observer as well, so the subscribers of
+	/// an upper layer see the events of the one below.
+	pub(crate) fn forward(&self, to: Observer) {
+		self.inner.lock().unwrap().forward = Some(to);
+	}
+}
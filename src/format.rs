@@ -1,5 +1,7 @@
+#[cfg(feature = "transcode")]
+use std::fmt;
 use bytes::{BufMut, Bytes, BytesMut};
-use serde::{ser::Serialize, de::DeserializeOwned};
+use serde::{ser::Serialize, de::{Deserialize, DeserializeOwned}};
 
 /// Trait that conflates serialization and deserialization.
 pub trait Format: Send + Sync + 'static {
@@ -13,16 +15,29 @@ pub trait Format: Send + Sync + 'static {
 	fn deserialize<T: DeserializeOwned>(buffer: &Bytes) -> Result<T, Self::DeserializeError>;
 }
 
+/// A `Format` that can deserialize values borrowing from the buffer.
+pub trait FormatRef: Format {
+	/// Deserialize a value borrowing from a buffer.
+	fn deserialize_ref<'a, T: Deserialize<'a>>(buffer: &'a [u8]) -> Result<T, Self::DeserializeError>;
+}
+
+/// No format, the payload is opaque and can't be (de)serialized.
 impl Format for () {
 	type SerializeError = ();
 	type DeserializeError = ();
 
 	fn serialize<T: Serialize>(_value: &T, _buffer: &mut BytesMut) -> Result<(), ()> {
-		unreachable!("u wot");
+		Err(())
 	}
 
 	fn deserialize<T: DeserializeOwned>(_buffer: &Bytes) -> Result<T, ()> {
-		unreachable!("u wot");
+		Err(())
+	}
+}
+
+impl FormatRef for () {
+	fn deserialize_ref<'a, T: Deserialize<'a>>(_buffer: &'a [u8]) -> Result<T, ()> {
+		Err(())
 	}
 }
 
@@ -44,3 +59,50 @@ impl Format for MessagePack {
 		msgpack::decode::from_slice(buffer)
 	}
 }
+
+#[cfg(feature = "msgpack")]
+impl FormatRef for MessagePack {
+	fn deserialize_ref<'a, T: Deserialize<'a>>(buffer: &'a [u8]) -> Result<T, Self::DeserializeError> {
+		msgpack::decode::from_slice(buffer)
+	}
+}
+
+/// Error while converting a payload from one `Format` to another.
+#[cfg(feature = "transcode")]
+pub enum TranscodeError<A: Format, B: Format> {
+	/// The payload could not be deserialized with the source `Format`.
+	Deserialize(A::DeserializeError),
+
+	/// The value could not be serialized with the target `Format`.
+	Serialize(B::SerializeError),
+}
+
+#[cfg(feature = "transcode")]
+impl<A: Format, B: Format> fmt::Debug for TranscodeError<A, B>
+	where A::DeserializeError: fmt::Debug,
+	      B::SerializeError: fmt::Debug
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			TranscodeError::Deserialize(error) =>
+				write!(f, "TranscodeError::Deserialize({:?})", error),
+
+			TranscodeError::Serialize(error) =>
+				write!(f, "TranscodeError::Serialize({:?})", error),
+		}
+	}
+}
+
+/// Convert a payload from format `A` to format `B`, going through an
+/// intermediate `serde_value::Value`.
+#[cfg(feature = "transcode")]
+pub fn transcode<A: Format, B: Format>(buffer: &Bytes) -> Result<Bytes, TranscodeError<A, B>> {
+	let value = A::deserialize::<serde_value::Value>(buffer)
+		.map_err(TranscodeError::Deserialize)?;
+
+	let mut bytes = BytesMut::new();
+	B::serialize(&value, &mut bytes)
+		.map_err(TranscodeError::Serialize)?;
+
+	Ok(bytes.freeze())
+}
@@ -4,30 +4,192 @@ pub mod reframe;
 pub use crate::reframe::{Reframe, Reframed};
 
 pub mod format;
-pub use crate::format::Format;
+pub use crate::format::{Format, FormatRef};
 
 pub mod packet;
 pub use crate::packet::Packet;
 
+mod body;
+pub use crate::body::Body;
+
 mod message;
-pub use crate::message::Message;
+pub use crate::message::{Message, Mode};
 
 mod session;
-pub use crate::session::Session;
+pub use crate::session::{Session, Peer, Opener, Exhausted, Role};
+
+pub mod auth;
+
+pub mod event;
+pub use crate::event::Event;
+
+pub mod compress;
+
+#[cfg(any(feature = "crc32c", feature = "xxhash"))]
+pub mod checksum;
+
+pub mod pipe;
+
+pub mod udp;
+pub mod process;
+pub mod serial;
+pub mod pool;
+
+#[cfg(unix)]
+pub mod unix;
+
+#[cfg(target_os = "linux")]
+pub mod shm;
+
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(feature = "noise")]
+pub mod noise;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(feature = "quic")]
+pub mod quic;
 
 mod codec;
-pub use crate::codec::{Codec, Packets, Sessions};
+pub use crate::codec::{Codec, Vectored, Packets, Streaming, Sessions};
 
 use std::marker::Unpin;
-use tokio::{codec::Framed, io::{AsyncRead, AsyncWrite}};
+use tokio::{codec::{Framed, FramedRead}, io::{self, AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt}};
 
 pub fn mi<F, S>(socket: S) -> Reframed<Sessions<F>>
   where F: Format,
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static
 {
-  let packets = Framed::new(socket, Codec);
+  mi_with(socket, None)
+}
+
+/// Like `mi`, but cookie ownership is enforced for the given end of the
+/// connection, the peer must be using the opposite role.
+pub fn mi_as<F, S>(socket: S, role: Role) -> Reframed<Sessions<F>>
+  where F: Format,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+  mi_with(socket, Some(role))
+}
+
+pub(crate) fn mi_with<F, S>(socket: S, role: Option<Role>) -> Reframed<Sessions<F>>
+  where F: Format,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+  let packets = Framed::new(socket, Codec::default());
+  let packets = Reframed::<Packets<F>>::new(packets);
+  let packets = packets.into_sessions_with(role);
+
+  packets
+}
+
+/// Like `mi`, but payloads are written with vectored writes instead of being
+/// copied into the write buffer.
+pub fn mi_vectored<F, S>(socket: S) -> Reframed<Sessions<F>>
+  where F: Format,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+  let (reader, writer) = io::split(socket);
+  let packets = Reframed::<Packets<F>>::from_parts(FramedRead::new(reader, Codec::default()), Vectored::new(writer));
+  let packets = packets.into_sessions();
+
+  packets
+}
+
+/// Like `mi`, but payloads spanning multiple fragments are exposed as a `Body`
+/// as they arrive instead of being reassembled in memory.
+///
+/// Every received `Body` must be drained or dropped, one left pending stalls
+/// the whole connection.
+pub fn mi_streaming<F, S>(socket: S) -> Reframed<Sessions<F>>
+  where F: Format,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+  let packets = Framed::new(socket, Codec::default());
+  let packets = Reframed::<Streaming<F>>::new(packets);
+  let packets = packets.into_sessions();
+
+  packets
+}
+
+/// Like `mi`, but payloads are compressed with an algorithm negotiated with
+/// the peer, which must be using compression as well.
+pub fn mi_compressed<F, S>(socket: S) -> Reframed<Sessions<F>>
+  where F: Format,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+  let packets = Framed::new(socket, Codec::default());
   let packets = Reframed::<Packets<F>>::new(packets);
+  let observer = packets.handle().clone();
+
+  let packets = Reframed::<compress::Compressed<F>>::new(packets);
   let packets = Reframed::<Sessions<F>>::new(packets);
+  observer.forward(packets.handle().observer().clone());
 
   packets
 }
+
+/// Like `mi`, but the whole byte stream is compressed with a single zstd
+/// context, so repeated message shapes compress well even when small.
+///
+/// Both peers must be using this with the same `Options`.
+#[cfg(feature = "zstd")]
+pub fn mi_zstd<F, S>(socket: S, options: compress::Options) -> Result<Reframed<Sessions<F>>, io::Error>
+  where F: Format,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+  Ok(mi(compress::Context::new(socket, options)?))
+}
+
+/// Announces support for wide headers.
+const WIDE: u8 = 1;
+
+/// Announces being the initiator.
+const INITIATOR: u8 = 2;
+
+/// Like `mi_as`, but wide headers carrying 32-bit cookies are used, so far
+/// more sessions can be open at once.
+///
+/// Both peers must be using this with opposite roles, a peer using `mi` never
+/// announces wide headers and would take the announcement as the start of a
+/// header.
+pub async fn mi_wide<F, S>(mut socket: S, role: Role) -> Result<Reframed<Sessions<F>>, io::Error>
+  where F: Format,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+  let ours = WIDE | if role == Role::Initiator { INITIATOR } else { 0 };
+  socket.write_all(&[ours]).await?;
+  socket.flush().await?;
+
+  let mut theirs = [0];
+  socket.read_exact(&mut theirs).await?;
+
+  if theirs[0] & WIDE == 0 {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "peer does not support wide headers"));
+  }
+
+  if theirs[0] & INITIATOR == ours & INITIATOR {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "peer is using the same role"));
+  }
+
+  let packets = Framed::new(socket, Codec::wide());
+  let packets = Reframed::<Packets<F>>::new(packets);
+  let packets = packets.into_sessions_as(role);
+  packets.handle().widen();
+
+  Ok(packets)
+}
+
+/// Create two connected ends over an in-memory pipe.
+pub fn pair<F: Format>() -> (Reframed<Sessions<F>>, Reframed<Sessions<F>>) {
+  pair_with(pipe::Options::default())
+}
+
+/// Create two connected ends over an in-memory pipe with the given options.
+pub fn pair_with<F: Format>(options: pipe::Options) -> (Reframed<Sessions<F>>, Reframed<Sessions<F>>) {
+  let (left, right) = pipe::duplex(options);
+  (mi_as(left, Role::Initiator), mi_as(right, Role::Acceptor))
+}
@@ -1,7 +1,9 @@
 use std::{fmt, marker::PhantomData};
-use bytes::{Bytes, BytesMut};
-use serde::{ser::Serialize, de::DeserializeOwned};
-use crate::{packet::{self, Packet}, Format};
+use bytes::Bytes;
+use serde::{ser::Serialize, de::{Deserialize, DeserializeOwned}};
+use crate::{packet::{self, Packet}, Format, format::FormatRef, Body};
+#[cfg(unix)]
+use crate::unix;
 
 /// A message.
 #[derive(Clone)]
@@ -9,6 +11,17 @@ pub struct Message<F = ()> {
 	pub(crate) mode: Mode,
 	pub(crate) bytes: Bytes,
 
+	/// The wire representation of the message, the cookie is patched in once
+	/// the message is bound to a session.
+	pub(crate) frame: Option<Bytes>,
+
+	/// The payload as a lazily received or sent stream of chunks.
+	pub(crate) body: Option<Body>,
+
+	/// File descriptors sent along the payload.
+	#[cfg(unix)]
+	pub(crate) fds: unix::Descriptors,
+
 	_marker: PhantomData<F>,
 }
 
@@ -33,6 +46,10 @@ impl<F> From<Packet<F>> for Message<F> {
 			},
 
 			bytes: packet.bytes,
+			frame: packet.frame,
+			body: packet.body,
+			#[cfg(unix)]
+			fds: packet.fds,
 			_marker: PhantomData,
 		}
 	}
@@ -51,32 +68,97 @@ pub enum Mode {
 	End,
 }
 
-impl Message<()> {
-	/// Decide on the `Format` to use for this packet.
-	pub fn with_format<F: Format>(self) -> Self {
-		Self {
+impl Mode {
+	/// The packet `Cookie` for this mode within the given session.
+	pub(crate) fn cookie(self, cookie: u32) -> packet::Cookie {
+		match self {
+			Mode::NoReply =>
+				packet::Cookie::Oneshot,
+
+			Mode::More =>
+				packet::Cookie::Stream(cookie),
+
+			Mode::End =>
+				packet::Cookie::Single(cookie),
+		}
+	}
+}
+
+impl<A> Message<A> {
+	/// Decide on the `Format` to use for this message, the payload is left
+	/// untouched.
+	pub fn with_format<B: Format>(self) -> Message<B> {
+		Message {
 			mode: self.mode,
 			bytes: self.bytes,
+			frame: self.frame,
+			body: self.body,
+			#[cfg(unix)]
+			fds: self.fds,
 
 			_marker: PhantomData,
 		}
 	}
 }
 
+#[cfg(feature = "transcode")]
+impl<A: Format> Message<A> {
+	/// Convert the payload of the message to another `Format`.
+	pub fn transcode<B: Format>(&self) -> Result<Message<B>, crate::format::TranscodeError<A, B>> {
+		Ok(Message {
+			mode: self.mode,
+			bytes: crate::format::transcode::<A, B>(&self.bytes)?,
+			frame: None,
+			body: None,
+			#[cfg(unix)]
+			fds: self.fds.clone(),
+
+			_marker: PhantomData,
+		})
+	}
+}
+
 impl<F: Format> Message<F> {
 	/// Create a packet from a `cookie` and payload.
 	pub fn new(mode: Mode, payload: Bytes) -> Self {
-		Self { mode, bytes: payload, _marker: PhantomData }
+		Self {
+			mode: mode,
+			bytes: payload,
+			frame: None,
+			body: None,
+			#[cfg(unix)]
+			fds: Default::default(),
+
+			_marker: PhantomData,
+		}
+	}
+
+	/// Create a message from a payload that will be fragmented lazily as it's
+	/// sent.
+	pub fn streamed(mode: Mode, body: Body) -> Self {
+		Self {
+			mode: mode,
+			bytes: Bytes::new(),
+			frame: None,
+			body: Some(body),
+			#[cfg(unix)]
+			fds: Default::default(),
+
+			_marker: PhantomData,
+		}
 	}
 
 	/// Create a new oneshot packet from a value.
 	pub fn no_reply<T: Serialize>(value: &T) -> Result<Self, F::SerializeError> {
-		let mut bytes = BytesMut::new();
-		F::serialize(value, &mut bytes)?;
+		let (bytes, frame) = packet::frame::<F, T>(Mode::NoReply.cookie(0), value)?;
 
 		Ok(Self {
 			mode:  Mode::NoReply,
-			bytes: bytes.freeze(),
+			bytes: bytes,
+			frame: frame,
+			body:  None,
+			#[cfg(unix)]
+			fds:   Default::default(),
 
 			_marker: PhantomData,
 		})
@@ -84,12 +166,15 @@ impl<F: Format> Message<F> {
 
 	/// Create a new message marking more messages incoming.
 	pub fn more<T: Serialize>(value: &T) -> Result<Self, F::SerializeError> {
-		let mut bytes = BytesMut::new();
-		F::serialize(value, &mut bytes)?;
+		let (bytes, frame) = packet::frame::<F, T>(Mode::More.cookie(0), value)?;
 
 		Ok(Self {
 			mode:  Mode::More,
-			bytes: bytes.freeze(),
+			bytes: bytes,
+			frame: frame,
+			body:  None,
+			#[cfg(unix)]
+			fds:   Default::default(),
 
 			_marker: PhantomData,
 		})
@@ -97,17 +182,45 @@ impl<F: Format> Message<F> {
 
 	/// Create a new ending message from a value.
 	pub fn end<T: Serialize>(value: &T) -> Result<Self, F::SerializeError> {
-		let mut bytes = BytesMut::new();
-		F::serialize(value, &mut bytes)?;
+		let (bytes, frame) = packet::frame::<F, T>(Mode::End.cookie(0), value)?;
 
 		Ok(Self {
 			mode:  Mode::End,
-			bytes: bytes.freeze(),
+			bytes: bytes,
+			frame: frame,
+			body:  None,
+			#[cfg(unix)]
+			fds:   Default::default(),
 
 			_marker: PhantomData,
 		})
 	}
 
+	/// Bind the message to a session, turning it into a `Packet`.
+	pub(crate) fn into_packet(self, cookie: u32) -> Packet<F> {
+		// Frames carry the standard header, which can't hold wider cookies.
+		let patchable = cookie <= packet::MAX_COOKIE;
+		let cookie = self.mode.cookie(cookie);
+
+		#[allow(unused_mut)]
+		let mut packet = if let Some(body) = self.body {
+			Packet::streamed(cookie, body)
+		}
+		else if let Some(frame) = self.frame.filter(|_| patchable) {
+			// Drop the payload view so the frame can be patched without a copy.
+			drop(self.bytes);
+			Packet::from_frame(cookie, packet::patch(frame, cookie))
+		}
+		else {
+			Packet::new(cookie, self.bytes)
+		};
+
+		#[cfg(unix)]
+		packet.fds = self.fds;
+
+		packet
+	}
+
 	/// The message mode.
 	pub fn mode(&self) -> Mode {
 		self.mode
@@ -118,8 +231,34 @@ impl<F: Format> Message<F> {
 		&self.bytes
 	}
 
+	/// The streamed payload of the message, if any, in which case `bytes` is
+	/// empty.
+	pub fn body(&self) -> Option<&Body> {
+		self.body.as_ref()
+	}
+
+	/// Attach file descriptors to be sent along the message.
+	#[cfg(unix)]
+	pub fn attach(self, fds: Vec<unix::Fd>) -> Self {
+		self.fds.extend(fds);
+		self
+	}
+
+	/// Take the file descriptors received along the message.
+	#[cfg(unix)]
+	pub fn take_fds(&self) -> Vec<unix::Fd> {
+		self.fds.take()
+	}
+
 	/// Try to deserialize the payload to a value.
 	pub fn cast<T: DeserializeOwned>(&self) -> Result<T, F::DeserializeError> {
 		F::deserialize(&self.bytes)
 	}
 }
+
+impl<F: FormatRef> Message<F> {
+	/// Try to deserialize the payload to a value borrowing from the message.
+	pub fn cast_ref<'a, T: Deserialize<'a>>(&'a self) -> Result<T, F::DeserializeError> {
+		F::deserialize_ref(&self.bytes)
+	}
+}
@@ -0,0 +1,213 @@
+//! Noise protocol encrypted transport.
+//!
+//! Peers authenticate each other through their static keys, which the
+//! application can inspect with `Transport::remote_static` before handing the
+//! transport to `mi`.
+
+use std::{io, pin::Pin, task::{Context, Poll}};
+use bytes::{BufMut, BytesMut, ByteOrder, BigEndian};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use snow::{Builder, HandshakeState, TransportState};
+
+/// The Noise protocol used for the handshake and transport.
+pub const PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Maximum length of a Noise message.
+const MAX_MESSAGE: usize = 0xffff;
+
+/// Length of the authentication tag appended to each message.
+const TAG: usize = 16;
+
+/// Size of the scratch buffer used for reading.
+const CHUNK: usize = 8 * 1024;
+
+fn error(error: snow::Error) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn builder() -> Builder<'static> {
+	Builder::new(PARAMS.parse().unwrap())
+}
+
+/// A static keypair.
+pub struct Keypair {
+	/// The private key, keep it secret.
+	pub private: Vec<u8>,
+
+	/// The public key, as seen by peers.
+	pub public: Vec<u8>,
+}
+
+/// Generate a new static keypair.
+pub fn generate() -> Result<Keypair, io::Error> {
+	let keypair = builder().generate_keypair().map_err(error)?;
+
+	Ok(Keypair {
+		private: keypair.private,
+		public: keypair.public,
+	})
+}
+
+/// Perform the handshake as the initiator.
+pub async fn initiate<S>(socket: S, private: &[u8]) -> Result<Transport<S>, io::Error>
+	where S: AsyncRead + AsyncWrite + Unpin
+{
+	let state = builder().local_private_key(private).build_initiator().map_err(error)?;
+	handshake(socket, state).await
+}
+
+/// Perform the handshake as the responder.
+pub async fn respond<S>(socket: S, private: &[u8]) -> Result<Transport<S>, io::Error>
+	where S: AsyncRead + AsyncWrite + Unpin
+{
+	let state = builder().local_private_key(private).build_responder().map_err(error)?;
+	handshake(socket, state).await
+}
+
+async fn handshake<S>(mut socket: S, mut state: HandshakeState) -> Result<Transport<S>, io::Error>
+	where S: AsyncRead + AsyncWrite + Unpin
+{
+	let mut buffer = vec![0; MAX_MESSAGE];
+	let mut message = vec![0; MAX_MESSAGE];
+
+	while !state.is_handshake_finished() {
+		if state.is_my_turn() {
+			let length = state.write_message(&[], &mut buffer).map_err(error)?;
+
+			let mut header = [0; 2];
+			BigEndian::write_u16(&mut header, length as u16);
+
+			socket.write_all(&header).await?;
+			socket.write_all(&buffer[.. length]).await?;
+			socket.flush().await?;
+		}
+		else {
+			let mut header = [0; 2];
+			socket.read_exact(&mut header).await?;
+
+			let length = usize::from(BigEndian::read_u16(&header));
+			socket.read_exact(&mut message[.. length]).await?;
+
+			state.read_message(&message[.. length], &mut buffer).map_err(error)?;
+		}
+	}
+
+	Ok(Transport {
+		inner: socket,
+		state: state.into_transport_mode().map_err(error)?,
+
+		incoming: BytesMut::new(),
+		plain: BytesMut::new(),
+		outgoing: BytesMut::new(),
+		scratch: buffer,
+	})
+}
+
+/// An `AsyncRead + AsyncWrite` encrypting everything with the keys agreed on
+/// during the handshake.
+pub struct Transport<S> {
+	inner: S,
+	state: TransportState,
+
+	incoming: BytesMut,
+	plain: BytesMut,
+	outgoing: BytesMut,
+	scratch: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Transport<S> {
+	/// The static public key of the peer.
+	pub fn remote_static(&self) -> Option<&[u8]> {
+		self.state.get_remote_static()
+	}
+
+	fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		while !self.outgoing.is_empty() {
+			let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing))?;
+
+			if written == 0 {
+				return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+			}
+
+			self.outgoing.advance(written);
+		}
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Transport<S> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+
+		loop {
+			if !this.plain.is_empty() {
+				let length = buf.len().min(this.plain.len());
+				buf[.. length].copy_from_slice(&this.plain[.. length]);
+				this.plain.advance(length);
+
+				return Poll::Ready(Ok(length));
+			}
+
+			if this.incoming.len() >= 2 {
+				let length = usize::from(BigEndian::read_u16(&this.incoming));
+
+				if this.incoming.len() >= 2 + length {
+					let message = this.incoming.split_to(2 + length);
+					let length = this.state.read_message(&message[2 ..], &mut this.scratch).map_err(error)?;
+
+					this.plain.extend_from_slice(&this.scratch[.. length]);
+					continue;
+				}
+			}
+
+			let mut chunk = [0; CHUNK];
+			let read = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+
+			if read == 0 {
+				// A partial message would otherwise silently truncate the stream.
+				if !this.incoming.is_empty() {
+					return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed within a message")));
+				}
+
+				return Poll::Ready(Ok(0));
+			}
+
+			this.incoming.extend_from_slice(&chunk[.. read]);
+		}
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Transport<S> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+
+		if this.outgoing.len() >= MAX_MESSAGE {
+			ready!(this.poll_drain(cx))?;
+		}
+
+		let length = buf.len().min(MAX_MESSAGE - TAG);
+		let encrypted = this.state.write_message(&buf[.. length], &mut this.scratch).map_err(error)?;
+
+		this.outgoing.reserve(2 + encrypted);
+		this.outgoing.put_u16_be(encrypted as u16);
+		this.outgoing.put_slice(&this.scratch[.. encrypted]);
+
+		Poll::Ready(Ok(length))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		let this = self.get_mut();
+
+		ready!(this.poll_drain(cx))?;
+		Pin::new(&mut this.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		let this = self.get_mut();
+
+		ready!(this.poll_drain(cx))?;
+		Pin::new(&mut this.inner).poll_shutdown(cx)
+	}
+}
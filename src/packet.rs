@@ -1,7 +1,9 @@
-use std::{fmt, marker::PhantomData};
-use bytes::{Bytes, BytesMut};
-use serde::{ser::Serialize, de::DeserializeOwned};
-use crate::Format;
+use std::{io, fmt, marker::PhantomData};
+use bytes::{BufMut, Bytes, BytesMut, ByteOrder, BigEndian};
+use serde::{ser::Serialize, de::{Deserialize, DeserializeOwned}};
+use crate::{Format, format::FormatRef, Body};
+#[cfg(unix)]
+use crate::unix;
 
 /// The cookie for a packet.
 #[derive(Copy, Clone, Debug)]
@@ -10,16 +12,31 @@ pub enum Cookie {
 	Oneshot,
 
 	/// There are no more packets in this stream.
-	Single(u16),
+	Single(u32),
 
 	/// There are more packets in this stream.
-	Stream(u16),
+	Stream(u32),
 }
 
+/// Bit marking a `Stream` cookie.
+const STREAM: u32 = 0x8000_0000;
+
+/// The largest cookie the standard header can carry.
+pub const MAX_COOKIE: u32 = 0x7fff;
+
+/// The largest cookie the wide header can carry.
+pub const MAX_WIDE_COOKIE: u32 = 0x7fff_ffff;
+
+/// Size of the standard header.
+pub const SIZE: usize = 4;
+
+/// Size of the wide header.
+pub const WIDE_SIZE: usize = 6;
+
 /// Header for a `protociolla::Packet`.
 #[derive(Copy, Clone, Debug)]
 pub struct Header {
-	pub(crate) cookie: u16,
+	pub(crate) cookie: u32,
 	pub(crate) length: u16,
 }
 
@@ -42,7 +59,7 @@ impl Header {
 	}
 
 	/// Construct a `Header` with a `Single` cookie.
-	pub fn single(cookie: u16, length: Option<usize>) -> Self {
+	pub fn single(cookie: u32, length: Option<usize>) -> Self {
 		Self {
 			cookie: cookie,
 			length: Self::make_length(length),
@@ -50,16 +67,111 @@ impl Header {
 	}
 
 	/// Construct a `Header` with a `Stream` cookie.
-	pub fn stream(cookie: u16, length: Option<usize>) -> Self {
+	pub fn stream(cookie: u32, length: Option<usize>) -> Self {
 		Self {
-			cookie: cookie | 0x8000,
+			cookie: cookie | STREAM,
 			length: Self::make_length(length),
 		}
 	}
 
+	/// Construct a `Header` for the given `Cookie`.
+	pub(crate) fn new(cookie: Cookie, length: Option<usize>) -> Self {
+		match cookie {
+			Cookie::Oneshot =>
+				Self::oneshot(length),
+
+			Cookie::Single(cookie) =>
+				Self::single(cookie, length),
+
+			Cookie::Stream(cookie) =>
+				Self::stream(cookie, length),
+		}
+	}
+
+	/// Read a standard header from the start of the buffer.
+	pub(crate) fn read(buffer: &[u8]) -> Self {
+		let cookie = BigEndian::read_u16(&buffer[0..]);
+
+		Self {
+			cookie: u32::from(cookie & 0x7fff) | if cookie & 0x8000 != 0 { STREAM } else { 0 },
+			length: BigEndian::read_u16(&buffer[2..]),
+		}
+	}
+
+	/// Read a wide header from the start of the buffer.
+	pub(crate) fn read_wide(buffer: &[u8]) -> Self {
+		Self {
+			cookie: BigEndian::read_u32(&buffer[0..]),
+			length: BigEndian::read_u16(&buffer[4..]),
+		}
+	}
+
+	/// Fail if the cookie doesn't fit in the standard header.
+	pub(crate) fn check(&self) -> Result<(), io::Error> {
+		if self.cookie & !STREAM > MAX_COOKIE {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "cookie too wide for the standard header"));
+		}
+
+		Ok(())
+	}
+
+	/// Write the standard header at the start of the buffer, failing if the
+	/// cookie is over `MAX_COOKIE`.
+	pub(crate) fn write(&self, buffer: &mut [u8]) -> Result<(), io::Error> {
+		self.check()?;
+
+		let stream = if self.has_more_packets() { 0x8000 } else { 0 };
+		BigEndian::write_u16(&mut buffer[0..], self.cookie as u16 | stream);
+		BigEndian::write_u16(&mut buffer[2..], self.length);
+
+		Ok(())
+	}
+
+	/// Write the wide header at the start of the buffer.
+	pub(crate) fn write_wide(&self, buffer: &mut [u8]) {
+		BigEndian::write_u32(&mut buffer[0..], self.cookie);
+		BigEndian::write_u16(&mut buffer[4..], self.length);
+	}
+
+	/// Append the header to the buffer, failing if the cookie doesn't fit in
+	/// the standard header.
+	pub(crate) fn put(&self, buffer: &mut BytesMut, wide: bool) -> Result<(), io::Error> {
+		if !wide {
+			self.check()?;
+		}
+
+		let start = buffer.len();
+		let size = if wide { WIDE_SIZE } else { SIZE };
+
+		buffer.reserve(size);
+		buffer.put_slice(&[0; WIDE_SIZE][.. size]);
+
+		if wide {
+			self.write_wide(&mut buffer[start ..]);
+			Ok(())
+		}
+		else {
+			self.write(&mut buffer[start ..])
+		}
+	}
+
+	/// Get the `Cookie` for the packet this fragment belongs to.
+	pub(crate) fn to_cookie(&self) -> Cookie {
+		match self.cookie() {
+			None =>
+				Cookie::Oneshot,
+
+			Some(cookie) if self.has_more_packets() =>
+				Cookie::Stream(cookie),
+
+			Some(cookie) =>
+				Cookie::Single(cookie),
+		}
+	}
+
 	/// Get the cookie, if any.
-	pub fn cookie(&self) -> Option<u16> {
-		match self.cookie & !0x8000 {
+	pub fn cookie(&self) -> Option<u32> {
+		match self.cookie & !STREAM {
 			0 => None,
 			v => Some(v)
 		}
@@ -72,7 +184,7 @@ impl Header {
 
 	/// Check if there are more packets in this stream.
 	pub fn has_more_packets(&self) -> bool {
-		(self.cookie & 0x8000) != 0
+		(self.cookie & STREAM) != 0
 	}
 
 	/// Check if there are more data fragments in this packet.
@@ -81,12 +193,57 @@ impl Header {
 	}
 }
 
+/// Serialize a value straight into its wire representation, leaving room for
+/// the header in front of the payload.
+///
+/// Returns the payload and, if it fits in a single fragment with a standard
+/// header, the frame the payload is a view of.
+pub(crate) fn frame<F: Format, T: Serialize>(cookie: Cookie, value: &T) -> Result<(Bytes, Option<Bytes>), F::SerializeError> {
+	let mut buffer = BytesMut::with_capacity(64);
+	buffer.put_u32_be(0);
+	F::serialize(value, &mut buffer)?;
+
+	let length = buffer.len() - 4;
+	if length > 0xfffe || Header::new(cookie, Some(length)).write(&mut buffer[..4]).is_err() {
+		return Ok((buffer.split_off(4).freeze(), None));
+	}
+
+	let frame = buffer.freeze();
+
+	Ok((frame.slice_from(4), Some(frame)))
+}
+
+/// Patch the header of a frame in place for a different `Cookie`, which must
+/// fit in the standard header.
+pub(crate) fn patch(frame: Bytes, cookie: Cookie) -> Bytes {
+	let mut frame = match frame.try_mut() {
+		Ok(frame) => frame,
+		Err(frame) => BytesMut::from(&frame[..]),
+	};
+
+	let length = frame.len() - 4;
+	Header::new(cookie, Some(length)).write(&mut frame[..4]).unwrap();
+
+	frame.freeze()
+}
+
 /// A fully formed packet (with defragmented payload).
 #[derive(Clone)]
 pub struct Packet<F = ()> {
 	pub(crate) cookie: Cookie,
 	pub(crate) bytes: Bytes,
 
+	/// The wire representation of the packet, if it was serialized directly
+	/// into one.
+	pub(crate) frame: Option<Bytes>,
+
+	/// The payload as a lazily received or sent stream of chunks.
+	pub(crate) body: Option<Body>,
+
+	/// File descriptors sent along the payload.
+	#[cfg(unix)]
+	pub(crate) fds: unix::Descriptors,
+
 	_marker: PhantomData<F>,
 }
 
@@ -96,58 +253,127 @@ impl<F> fmt::Debug for Packet<F> {
 	}
 }
 
-impl Packet<()> {
-	/// Decide on the `Format` to use for this packet.
-	pub fn with_format<F: Format>(self) -> Self {
-		Self {
+impl<A> Packet<A> {
+	/// Decide on the `Format` to use for this packet, the payload is left
+	/// untouched.
+	pub fn with_format<B: Format>(self) -> Packet<B> {
+		Packet {
 			cookie: self.cookie,
 			bytes: self.bytes,
+			frame: self.frame,
+			body: self.body,
+			#[cfg(unix)]
+			fds: self.fds,
 
 			_marker: PhantomData,
 		}
 	}
 }
 
+#[cfg(feature = "transcode")]
+impl<A: Format> Packet<A> {
+	/// Convert the payload of the packet to another `Format`.
+	pub fn transcode<B: Format>(&self) -> Result<Packet<B>, crate::format::TranscodeError<A, B>> {
+		Ok(Packet {
+			cookie: self.cookie,
+			bytes: crate::format::transcode::<A, B>(&self.bytes)?,
+			frame: None,
+			body: None,
+			#[cfg(unix)]
+			fds: self.fds.clone(),
+
+			_marker: PhantomData,
+		})
+	}
+}
+
 impl<F: Format> Packet<F> {
 	/// Create a packet from a `cookie` and payload.
 	pub fn new(cookie: Cookie, payload: Bytes) -> Self {
-		Self { cookie, bytes: payload, _marker: PhantomData }
+		Self {
+			cookie: cookie,
+			bytes: payload,
+			frame: None,
+			body: None,
+			#[cfg(unix)]
+			fds: Default::default(),
+
+			_marker: PhantomData,
+		}
+	}
+
+	/// Create a packet from a `cookie` and a payload that will be fragmented
+	/// lazily as it's sent.
+	pub fn streamed(cookie: Cookie, body: Body) -> Self {
+		Self {
+			cookie: cookie,
+			bytes: Bytes::new(),
+			frame: None,
+			body: Some(body),
+			#[cfg(unix)]
+			fds: Default::default(),
+
+			_marker: PhantomData,
+		}
+	}
+
+	/// Create a packet from a `cookie` and its wire representation.
+	pub(crate) fn from_frame(cookie: Cookie, frame: Bytes) -> Self {
+		Self {
+			cookie: cookie,
+			bytes: frame.slice_from(4),
+			frame: Some(frame),
+			body: None,
+			#[cfg(unix)]
+			fds: Default::default(),
+
+			_marker: PhantomData,
+		}
 	}
 
 	/// Create a new oneshot packet from a value.
 	pub fn oneshot<T: Serialize>(value: &T) -> Result<Self, F::SerializeError> {
-		let mut bytes = BytesMut::new();
-		F::serialize(value, &mut bytes)?;
+		let (bytes, frame) = frame::<F, T>(Cookie::Oneshot, value)?;
 
 		Ok(Self {
 			cookie: Cookie::Oneshot,
-			bytes:  bytes.freeze(),
+			bytes:  bytes,
+			frame:  frame,
+			body:   None,
+			#[cfg(unix)]
+			fds:    Default::default(),
 
 			_marker: PhantomData,
 		})
 	}
 
 	/// Create a new single packet from a value.
-	pub fn single<T: Serialize>(cookie: u16, value: &T) -> Result<Self, F::SerializeError> {
-		let mut bytes = BytesMut::new();
-		F::serialize(value, &mut bytes)?;
+	pub fn single<T: Serialize>(cookie: u32, value: &T) -> Result<Self, F::SerializeError> {
+		let (bytes, frame) = frame::<F, T>(Cookie::Single(cookie), value)?;
 
 		Ok(Self {
 			cookie: Cookie::Single(cookie),
-			bytes:  bytes.freeze(),
+			bytes:  bytes,
+			frame:  frame,
+			body:   None,
+			#[cfg(unix)]
+			fds:    Default::default(),
 
 			_marker: PhantomData,
 		})
 	}
 
 	/// Create a new stream packet from a value.
-	pub fn stream<T: Serialize>(cookie: u16, value: &T) -> Result<Self, F::SerializeError> {
-		let mut bytes = BytesMut::new();
-		F::serialize(value, &mut bytes)?;
+	pub fn stream<T: Serialize>(cookie: u32, value: &T) -> Result<Self, F::SerializeError> {
+		let (bytes, frame) = frame::<F, T>(Cookie::Stream(cookie), value)?;
 
 		Ok(Self {
 			cookie: Cookie::Stream(cookie),
-			bytes:  bytes.freeze(),
+			bytes:  bytes,
+			frame:  frame,
+			body:   None,
+			#[cfg(unix)]
+			fds:    Default::default(),
 
 			_marker: PhantomData,
 		})
@@ -163,8 +389,52 @@ impl<F: Format> Packet<F> {
 		&self.bytes
 	}
 
+	/// The streamed payload of the packet, if any, in which case `bytes` is
+	/// empty.
+	pub fn body(&self) -> Option<&Body> {
+		self.body.as_ref()
+	}
+
+	/// Replace the payload, keeping the cookie and any attached file
+	/// descriptors.
+	pub(crate) fn with_bytes(mut self, bytes: Bytes) -> Self {
+		self.bytes = bytes;
+		self.frame = None;
+		self.body = None;
+		self
+	}
+
+	/// Replace the payload with a streamed one, keeping the cookie and any
+	/// attached file descriptors.
+	pub(crate) fn with_body(mut self, body: Body) -> Self {
+		self.bytes = Bytes::new();
+		self.frame = None;
+		self.body = Some(body);
+		self
+	}
+
+	/// Attach file descriptors to be sent along the packet.
+	#[cfg(unix)]
+	pub fn attach(self, fds: Vec<unix::Fd>) -> Self {
+		self.fds.extend(fds);
+		self
+	}
+
+	/// Take the file descriptors received along the packet.
+	#[cfg(unix)]
+	pub fn take_fds(&self) -> Vec<unix::Fd> {
+		self.fds.take()
+	}
+
 	/// Try to deserialize the payload to a value.
 	pub fn cast<T: DeserializeOwned>(&self) -> Result<T, F::DeserializeError> {
 		F::deserialize(&self.bytes)
 	}
 }
+
+impl<F: FormatRef> Packet<F> {
+	/// Try to deserialize the payload to a value borrowing from the packet.
+	pub fn cast_ref<'a, T: Deserialize<'a>>(&'a self) -> Result<T, F::DeserializeError> {
+		F::deserialize_ref(&self.bytes)
+	}
+}
@@ -0,0 +1,150 @@
+//! In-memory duplex transport, with optional latency, bandwidth and chunking
+//! to exercise framing edge cases.
+
+use std::{io, pin::Pin, future::Future, num::NonZeroUsize, sync::{Arc, Mutex}, task::{Context, Poll, Waker}, time::{Duration, Instant}, collections::VecDeque};
+use bytes::Bytes;
+use tokio::{timer::{self, Delay}, io::{AsyncRead, AsyncWrite}};
+
+/// Settings for a pipe.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Options {
+	/// Delay before written bytes can be read.
+	pub latency: Option<Duration>,
+
+	/// Bytes per second that can go through the pipe.
+	pub bandwidth: Option<NonZeroUsize>,
+
+	/// Maximum number of bytes returned by a single read.
+	pub chunk: Option<NonZeroUsize>,
+}
+
+/// One direction of a pipe.
+#[derive(Default)]
+struct Channel {
+	chunks: VecDeque<(Instant, Bytes)>,
+	last: Option<Instant>,
+	closed: bool,
+	reader: Option<Waker>,
+}
+
+impl Channel {
+	fn close(&mut self) {
+		self.closed = true;
+
+		if let Some(waker) = self.reader.take() {
+			waker.wake();
+		}
+	}
+}
+
+/// One end of an in-memory duplex pipe.
+pub struct Pipe {
+	options: Options,
+	delay: Option<Delay>,
+
+	read: Arc<Mutex<Channel>>,
+	write: Arc<Mutex<Channel>>,
+}
+
+/// Create a connected pair of pipes.
+pub fn duplex(options: Options) -> (Pipe, Pipe) {
+	let left = Arc::new(Mutex::new(Channel::default()));
+	let right = Arc::new(Mutex::new(Channel::default()));
+
+	(Pipe { options, delay: None, read: left.clone(), write: right.clone() },
+	 Pipe { options, delay: None, read: right, write: left })
+}
+
+impl AsyncRead for Pipe {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+
+		loop {
+			if let Some(delay) = this.delay.as_mut() {
+				if Pin::new(delay).poll(cx).is_pending() {
+					return Poll::Pending;
+				}
+
+				this.delay = None;
+			}
+
+			let mut channel = this.read.lock().unwrap();
+
+			let ready = match channel.chunks.front() {
+				Some(&(ready, _)) =>
+					ready,
+
+				None if channel.closed =>
+					return Poll::Ready(Ok(0)),
+
+				None => {
+					channel.reader = Some(cx.waker().clone());
+					return Poll::Pending;
+				}
+			};
+
+			if ready > Instant::now() {
+				this.delay = Some(timer::delay(ready));
+				continue;
+			}
+
+			let chunk = &mut channel.chunks.front_mut().unwrap().1;
+			let length = buf.len()
+				.min(chunk.len())
+				.min(this.options.chunk.map_or(usize::max_value(), NonZeroUsize::get));
+
+			buf[.. length].copy_from_slice(&chunk[.. length]);
+			chunk.advance(length);
+
+			if chunk.is_empty() {
+				channel.chunks.pop_front();
+			}
+
+			return Poll::Ready(Ok(length));
+		}
+	}
+}
+
+impl AsyncWrite for Pipe {
+	fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+		let mut channel = self.write.lock().unwrap();
+
+		if channel.closed {
+			return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+		}
+
+		let now = Instant::now();
+		let mut ready = now + self.options.latency.unwrap_or_default();
+
+		if let Some(bandwidth) = self.options.bandwidth {
+			// The bytes go through after the ones before them.
+			ready = ready.max(channel.last.unwrap_or(now));
+			ready += Duration::from_secs_f64(buf.len() as f64 / bandwidth.get() as f64);
+		}
+
+		channel.last = Some(ready);
+		channel.chunks.push_back((ready, Bytes::from(buf)));
+
+		if let Some(waker) = channel.reader.take() {
+			waker.wake();
+		}
+
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		self.write.lock().unwrap().close();
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl Drop for Pipe {
+	fn drop(&mut self) {
+		self.write.lock().unwrap().close();
+		self.read.lock().unwrap().close();
+	}
+}
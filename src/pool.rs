@@ -0,0 +1,163 @@
+//! A pool of connections sessions are spread over.
+
+use std::{io, pin::Pin, future::Future, sync::{Arc, Weak, Mutex}, task::{Context, Poll}, time::{Duration, Instant}};
+use futures::{stream::{Stream, StreamExt}, sink::SinkExt};
+use tokio::{timer, sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}};
+use crate::{Format, Reframed, Sessions, Session, Opener};
+
+/// Initial delay before trying to reconnect.
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Maximum delay before trying to reconnect.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The openers of the connections in the pool, `None` while reconnecting.
+type Connections<F> = Mutex<Vec<Option<Opener<F>>>>;
+
+/// A pool of connections, new sessions are opened on the connection with the
+/// fewest open sessions.
+///
+/// Every connection is watched by a task that reconnects it once it breaks,
+/// backing off exponentially while that fails.
+///
+/// Sessions started by the peers come out of the pool stream.
+pub struct Pool<F, C> {
+	connect: Arc<Mutex<C>>,
+	connections: Arc<Connections<F>>,
+
+	incoming: UnboundedReceiver<Session<F>>,
+	sender: UnboundedSender<Session<F>>,
+}
+
+impl<F, C, O> Pool<F, C>
+	where F: Format,
+	      C: FnMut(usize) -> O + Send + 'static,
+	      O: Future<Output = Result<Reframed<Sessions<F>>, io::Error>> + Send + 'static
+{
+	/// Create a pool of `size` connections, each established by calling
+	/// `connect` with its index, so they can be spread over endpoints.
+	///
+	/// Fails only if no connection could be established.
+	pub async fn new(size: usize, connect: C) -> Result<Self, io::Error> {
+		let (sender, incoming) = unbounded_channel();
+		let pool = Self {
+			connect: Arc::new(Mutex::new(connect)),
+			connections: Arc::new(Mutex::new((0 .. size).map(|_| None).collect())),
+
+			incoming: incoming,
+			sender: sender,
+		};
+
+		let mut failure = None;
+
+		for index in 0 .. size {
+			let connection = match Self::connect(&pool.connect, index).await {
+				Ok(connection) =>
+					Some(connection),
+
+				Err(error) => {
+					failure = Some(error);
+					None
+				}
+			};
+
+			pool.watch(index, connection);
+		}
+
+		if pool.connections.lock().unwrap().iter().any(Option::is_some) {
+			Ok(pool)
+		}
+		else {
+			Err(failure.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "empty pool")))
+		}
+	}
+
+	/// Open a session on the least loaded connection.
+	pub fn open(&self) -> Result<Session<F>, io::Error> {
+		self.connections.lock().unwrap().iter().flatten()
+			.min_by_key(|opener| opener.sessions())
+			.ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no connection available"))?
+			.open()
+	}
+
+	/// The number of sessions open over every connection.
+	pub fn sessions(&self) -> usize {
+		self.connections.lock().unwrap().iter().flatten()
+			.map(|opener| opener.sessions())
+			.sum()
+	}
+
+	async fn connect(connect: &Mutex<C>, index: usize) -> Result<Reframed<Sessions<F>>, io::Error> {
+		let connecting = (connect.lock().unwrap())(index);
+		connecting.await
+	}
+
+	/// Forward the sessions started by the peer, and reconnect once the
+	/// connection breaks, until the pool is dropped.
+	fn watch(&self, index: usize, mut connection: Option<Reframed<Sessions<F>>>) {
+		if let Some(connection) = &connection {
+			self.connections.lock().unwrap()[index] = Some(connection.handle().clone());
+		}
+
+		let connect = self.connect.clone();
+		let connections = Arc::downgrade(&self.connections);
+		let mut sender = self.sender.clone();
+
+		tokio::spawn(async move {
+			let mut backoff = MIN_BACKOFF;
+
+			loop {
+				if let Some(mut connection) = connection.take() {
+					backoff = MIN_BACKOFF;
+
+					while let Some(Ok(session)) = connection.next().await {
+						if sender.send(session).await.is_err() {
+							return;
+						}
+					}
+
+					if !set(&connections, index, None) {
+						return;
+					}
+				}
+				else {
+					timer::delay(Instant::now() + backoff).await;
+					backoff = (backoff * 2).min(MAX_BACKOFF);
+				}
+
+				if connections.upgrade().is_none() {
+					return;
+				}
+
+				connection = Self::connect(&connect, index).await.ok();
+
+				if let Some(connection) = &connection {
+					if !set(&connections, index, Some(connection.handle().clone())) {
+						return;
+					}
+				}
+			}
+		});
+	}
+}
+
+/// Replace the opener of a connection, failing if the pool is gone.
+fn set<F>(connections: &Weak<Connections<F>>, index: usize, opener: Option<Opener<F>>) -> bool {
+	match connections.upgrade() {
+		Some(connections) => {
+			connections.lock().unwrap()[index] = opener;
+			true
+		}
+
+		None =>
+			false,
+	}
+}
+
+impl<F: Format, C> Stream for Pool<F, C> {
+	type Item = Session<F>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		Pin::new(&mut self.get_mut().incoming).poll_next(cx)
+	}
+}
@@ -0,0 +1,45 @@
+//! Child process transport, for plugins speaking over their standard input
+//! and output.
+
+use std::{io, process::Stdio};
+use futures::stream::{self, StreamExt};
+use tokio::{codec::{FramedRead, FramedWrite}, process::Command};
+use crate::{Format, Reframed, Codec, Packets, Sessions, Role};
+
+/// Spawn the command and build the session stack over its standard input and
+/// output, once the child exits the stack yields an error with its exit
+/// status.
+pub fn spawn<F: Format>(command: &mut Command) -> Result<Reframed<Sessions<F>>, io::Error> {
+	let mut child = command
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.spawn()?;
+
+	let stdin = child.stdin().take().unwrap();
+	let stdout = child.stdout().take().unwrap();
+
+	let stream = FramedRead::new(stdout, Codec::default()).chain(stream::once(async move {
+		Err(match child.await {
+			Ok(status) =>
+				io::Error::new(io::ErrorKind::ConnectionAborted, format!("child process exited with {}", status)),
+
+			Err(error) =>
+				error,
+		})
+	}));
+
+	let packets = Reframed::<Packets<F>>::from_parts(stream, FramedWrite::new(stdin, Codec::default()));
+	Ok(packets.into_sessions_as(Role::Initiator))
+}
+
+/// Build the session stack over the standard input and output of the current
+/// process, for use within a plugin.
+///
+/// Nothing else must be written to standard output.
+pub fn plugin<F: Format>() -> Reframed<Sessions<F>> {
+	let packets = Reframed::<Packets<F>>::from_parts(
+		FramedRead::new(tokio::io::stdin(), Codec::default()),
+		FramedWrite::new(tokio::io::stdout(), Codec::default()));
+
+	packets.into_sessions_as(Role::Acceptor)
+}
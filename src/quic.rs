@@ -0,0 +1,163 @@
+//! QUIC transport, every session is carried by its own bidirectional stream
+//! and every `Cookie::Oneshot` packet by its own unidirectional stream, so a
+//! lost packet only blocks the session it belongs to.
+//!
+//! Within a bidirectional stream fragments are framed with `Codec` as usual,
+//! the cookie is always `COOKIE` since the stream already identifies the
+//! session. Unidirectional streams carry the bare payload.
+
+use std::{io, pin::Pin, net::SocketAddr, task::{Context, Poll}};
+use bytes::Bytes;
+use futures::{future::TryFutureExt, stream::{self, Stream, StreamExt}, sink::SinkExt};
+use tokio::codec::{FramedRead, FramedWrite};
+use quinn::{NewConnection, Connecting, RecvStream, SendStream, ConnectionError, ReadToEndError};
+use crate::{Format, Reframed, reframe, Codec, Packets, Session, packet::{self, Packet}};
+
+/// The cookie used for packets within a session stream.
+const COOKIE: u32 = 1;
+
+/// Maximum size of a payload on a unidirectional stream.
+const MAX_ONESHOT: usize = 16 * 1024 * 1024;
+
+fn error(error: ConnectionError) -> io::Error {
+	io::Error::new(io::ErrorKind::ConnectionAborted, error)
+}
+
+/// Build a session over a bidirectional stream.
+fn session<F: Format>(send: SendStream, recv: RecvStream) -> Session<F> {
+	let packets = Reframed::<Packets<F>>::from_parts(FramedRead::new(recv, Codec::default()), FramedWrite::new(send, Codec::default()));
+	let (sink, mut stream) = packets.split();
+
+	let session = Session::new(COOKIE, sink);
+	let mut sender = session.sender();
+
+	tokio::spawn(async move {
+		while let Some(Ok(packet)) = stream.next().await {
+			if sender.send(packet).await.is_err() {
+				return;
+			}
+		}
+	});
+
+	session
+}
+
+/// A stream opened by the peer.
+enum Incoming {
+	Bi(SendStream, RecvStream),
+	Uni(RecvStream),
+}
+
+/// A QUIC connection to a peer, unlike the other transports it emits no
+/// lifecycle `Event`s.
+pub struct Connection<F = ()> {
+	connection: quinn::Connection,
+	incoming: Pin<Box<dyn Stream<Item = Result<Session<F>, io::Error>> + Send>>,
+}
+
+impl<F: Format> Connection<F> {
+	/// Use an established connection.
+	pub fn new(connection: NewConnection) -> Self {
+		let NewConnection { driver, connection, bi_streams, uni_streams, .. } = connection;
+		tokio::spawn(driver.unwrap_or_else(|_| ()));
+
+		let bi = bi_streams.map(|stream| stream.map(|(send, recv)| Incoming::Bi(send, recv)));
+		let uni = uni_streams.map(|stream| stream.map(Incoming::Uni));
+		let mut streams = stream::select(bi, uni);
+
+		let incoming = reframe::stream(|mut out| async move {
+			while let Some(stream) = streams.next().await {
+				match stream {
+					Ok(Incoming::Bi(send, recv)) => {
+						if out.send(Ok(session(send, recv))).await.is_err() {
+							return;
+						}
+					}
+
+					// Oneshot payloads are read in their own task, so a slow stream
+					// doesn't hold up the others.
+					Ok(Incoming::Uni(recv)) => {
+						let mut out = out.clone();
+
+						tokio::spawn(async move {
+							let session = match recv.read_to_end(MAX_ONESHOT).await {
+								Ok(payload) =>
+									Ok(Session::no_reply(Packet::new(packet::Cookie::Oneshot, Bytes::from(payload)))),
+
+								Err(ReadToEndError::TooLong) =>
+									Err(io::Error::new(io::ErrorKind::InvalidData, "oneshot payload too large")),
+
+								Err(ReadToEndError::Read(err)) =>
+									Err(io::Error::new(io::ErrorKind::ConnectionAborted, err)),
+							};
+
+							out.send(session).await.ok();
+						});
+					}
+
+					Err(err) => {
+						out.send(Err(error(err))).await.ok();
+						return;
+					}
+				}
+			}
+		});
+
+		Self {
+			connection,
+			incoming: Box::pin(incoming),
+		}
+	}
+
+	/// Open a new session on its own stream.
+	pub async fn open(&self) -> Result<Session<F>, io::Error> {
+		let (send, recv) = self.connection.open_bi().await.map_err(error)?;
+		Ok(session(send, recv))
+	}
+
+	/// Send a packet that takes no reply on its own stream, the cookie of the
+	/// packet is ignored.
+	pub async fn send(&self, packet: Packet<F>) -> Result<(), io::Error> {
+		let mut send = self.connection.open_uni().await.map_err(error)?;
+
+		if let Some(mut body) = packet.body {
+			while let Some(chunk) = body.next().await {
+				send.write_all(&chunk?).await
+					.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+			}
+		}
+		else {
+			send.write_all(&packet.bytes).await
+				.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+		}
+
+		send.finish().await
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+	}
+
+	/// The underlying QUIC connection.
+	pub fn connection(&self) -> &quinn::Connection {
+		&self.connection
+	}
+}
+
+impl<F: Format> Stream for Connection<F> {
+	type Item = Result<Session<F>, io::Error>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		Pin::new(&mut self.get_mut().incoming).poll_next(cx)
+	}
+}
+
+/// Connect to a peer through the given endpoint.
+pub async fn connect<F: Format>(endpoint: &quinn::Endpoint, address: &SocketAddr, name: &str) -> Result<Connection<F>, io::Error> {
+	let connecting = endpoint.connect(address, name)
+		.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+	accept(connecting).await
+}
+
+/// Finish establishing an incoming or outgoing connection.
+pub async fn accept<F: Format>(connecting: Connecting) -> Result<Connection<F>, io::Error> {
+	Ok(Connection::new(connecting.await.map_err(error)?))
+}
@@ -51,13 +51,17 @@ pub trait Reframe {
 
 	type Error: Send + 'static;
 
+	/// Access to the reframing beyond its stream and sink.
+	type Handle: Send + Unpin + 'static;
+
 	fn reframe(source: Source<Self::StreamFrom, Self::SinkFrom, Self::Error>) ->
-		Source<Self::StreamInto, Self::SinkInto, Self::Error>;
+		(Source<Self::StreamInto, Self::SinkInto, Self::Error>, Self::Handle);
 }
 
 pub struct Reframed<R: Reframe> {
 	stream: Pin<Box<dyn Stream<Item = Result<R::StreamInto, R::Error>> + Send>>,
 	sink: Pin<Box<dyn Sink<R::SinkInto, Error = R::Error> + Send>>,
+	handle: R::Handle,
 }
 
 impl<R: Reframe> Reframed<R> {
@@ -67,8 +71,32 @@ impl<R: Reframe> Reframed<R> {
 	}
 
 	pub fn from_parts(stream: impl Stream<Item = Result<R::StreamFrom, R::Error>> + Send + 'static, sink: impl Sink<R::SinkFrom, Error = R::Error> + Send + 'static) -> Reframed<R> {
-		let Source { stream, sink } = R::reframe(Source::new(stream, sink));
-		Reframed { stream, sink }
+		Self::from_parts_with(stream, sink, R::reframe)
+	}
+
+	/// Like `from_parts`, but reframing with the given function instead of
+	/// `Reframe::reframe`, for reframings that need more to start.
+	pub(crate) fn from_parts_with<T>(stream: impl Stream<Item = Result<R::StreamFrom, R::Error>> + Send + 'static, sink: impl Sink<R::SinkFrom, Error = R::Error> + Send + 'static, reframe: T) -> Reframed<R>
+		where T: FnOnce(Source<R::StreamFrom, R::SinkFrom, R::Error>) -> (Source<R::StreamInto, R::SinkInto, R::Error>, R::Handle)
+	{
+		let (Source { stream, sink }, handle) = reframe(Source::new(stream, sink));
+		Reframed { stream, sink, handle }
+	}
+
+	/// The handle to the reframing.
+	pub fn handle(&self) -> &R::Handle {
+		&self.handle
+	}
+
+	/// Transform every item coming out of the stream.
+	pub(crate) fn map_stream<M>(self, mut map: M) -> Self
+		where M: FnMut(R::StreamInto) -> R::StreamInto + Send + 'static
+	{
+		Reframed {
+			stream: Box::pin(self.stream.map(move |item| item.map(&mut map))),
+			sink: self.sink,
+			handle: self.handle,
+		}
 	}
 }
 
@@ -0,0 +1,294 @@
+//! Framing for lossy byte links, like serial lines.
+//!
+//! Every fragment, preceded by a sequence number and followed by its
+//! checksum, is COBS encoded and terminated by a zero byte, so after lost or
+//! garbled bytes the decoder picks up again at the next delimiter.
+//!
+//! Fragments that fail to decode are dropped, and the gap they leave in the
+//! sequence makes the decoder discard the rest of the packet they belong to,
+//! so a packet is either delivered whole or not at all. A packet made of a
+//! single fragment that goes missing is not noticed.
+
+use std::{io, collections::VecDeque};
+use tokio::{codec::{Framed, Decoder, Encoder}, io::{AsyncRead, AsyncWrite}};
+use bytes::{BufMut, Bytes, BytesMut, ByteOrder, BigEndian};
+use crate::{Format, Reframed, Packets, Sessions, packet};
+
+#[cfg(any(feature = "crc32c", feature = "xxhash"))]
+pub use crate::checksum::Algorithm;
+
+/// A checksum algorithm, there is none without the `crc32c` or `xxhash`
+/// features so frames can only go unchecked.
+#[cfg(not(any(feature = "crc32c", feature = "xxhash")))]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Algorithm { }
+
+#[cfg(not(any(feature = "crc32c", feature = "xxhash")))]
+impl Algorithm {
+	fn checksum(self, _buffer: &[u8]) -> u32 {
+		match self { }
+	}
+}
+
+/// The frame delimiter.
+const DELIMITER: u8 = 0;
+
+/// Marks the first fragment of a packet.
+const FIRST: u8 = 1;
+
+/// Length of the sequence number and flags preceding the header.
+const PREFIX: usize = 2;
+
+/// Maximum length of an encoded frame, anything longer is garbage.
+const MAX_FRAME: usize = (PREFIX + 4 + 0xffff + 4) * 255 / 254 + 2;
+
+fn stuff(input: &[u8], output: &mut BytesMut) {
+	output.reserve(input.len() + input.len() / 254 + 2);
+
+	let mut index = output.len();
+	let mut code = 1u8;
+	output.put_u8(0);
+
+	for &byte in input {
+		if byte != 0 {
+			output.put_u8(byte);
+			code += 1;
+		}
+
+		if byte == 0 || code == 0xff {
+			output[index] = code;
+			index = output.len();
+			code = 1;
+			output.put_u8(0);
+		}
+	}
+
+	output[index] = code;
+}
+
+fn unstuff(input: &[u8]) -> Option<BytesMut> {
+	let mut output = BytesMut::with_capacity(input.len());
+	let mut offset = 0;
+
+	while offset < input.len() {
+		let code = usize::from(input[offset]);
+
+		if code == 0 || offset + code > input.len() {
+			return None;
+		}
+
+		output.extend_from_slice(&input[offset + 1 .. offset + code]);
+		offset += code;
+
+		if code < 0xff && offset < input.len() {
+			output.put_u8(0);
+		}
+	}
+
+	Some(output)
+}
+
+/// A fragment as it was framed.
+struct Frame {
+	sequence: u8,
+	first: bool,
+	header: packet::Header,
+	payload: Bytes,
+}
+
+/// `tokio::{Decoder, Encoder}` like `Codec`, resynchronizing on frame
+/// delimiters.
+pub struct Codec {
+	algorithm: Option<Algorithm>,
+	dropped: usize,
+	discarded: usize,
+
+	/// Sequence number of the next outgoing fragment.
+	next: u8,
+
+	/// The last outgoing fragment was not the last of its packet.
+	sending: bool,
+
+	/// Sequence number of the next expected incoming fragment.
+	expected: Option<u8>,
+
+	/// Fragments are skipped until the first one of a packet.
+	syncing: bool,
+
+	/// Fragments of the incoming packet, held until it is complete.
+	partial: Vec<(packet::Header, Bytes)>,
+	ready: VecDeque<(packet::Header, Bytes)>,
+}
+
+impl Codec {
+	/// Create a codec, following each fragment with a checksum if an algorithm
+	/// is given.
+	pub fn new(algorithm: Option<Algorithm>) -> Self {
+		Self {
+			algorithm: algorithm,
+			dropped: 0,
+			discarded: 0,
+
+			next: 0,
+			sending: false,
+
+			expected: None,
+			syncing: true,
+
+			partial: Vec::new(),
+			ready: VecDeque::new(),
+		}
+	}
+
+	/// The number of frames dropped so far.
+	pub fn dropped(&self) -> usize {
+		self.dropped
+	}
+
+	/// The number of packets discarded so far because some of their fragments
+	/// were missing.
+	pub fn discarded(&self) -> usize {
+		self.discarded
+	}
+
+	fn check(&self, mut frame: BytesMut) -> Option<Frame> {
+		if let Some(algorithm) = self.algorithm {
+			if frame.len() < 4 {
+				return None;
+			}
+
+			let length = frame.len() - 4;
+			let expected = BigEndian::read_u32(&frame[length ..]);
+
+			if algorithm.checksum(&frame[.. length]) != expected {
+				return None;
+			}
+
+			frame.truncate(length);
+		}
+
+		if frame.len() < PREFIX + 4 {
+			return None;
+		}
+
+		let sequence = frame[0];
+		let first = frame[1] & FIRST != 0;
+
+		let header = packet::Header::read(&frame[PREFIX ..]);
+		if frame.len() - PREFIX - 4 != header.length() {
+			return None;
+		}
+
+		frame.advance(PREFIX + 4);
+		Some(Frame { sequence, first, header, payload: frame.freeze() })
+	}
+
+	/// Hold on to the fragment until its packet is complete, dropping packets
+	/// with missing fragments.
+	fn reassemble(&mut self, frame: Frame) {
+		let gap = self.expected.map_or(false, |expected| expected != frame.sequence);
+		self.expected = Some(frame.sequence.wrapping_add(1));
+
+		if gap {
+			if !self.partial.is_empty() || !frame.first {
+				self.discarded += 1;
+			}
+
+			self.partial.clear();
+			self.syncing = true;
+		}
+
+		if self.syncing {
+			if !frame.first {
+				return;
+			}
+
+			self.syncing = false;
+		}
+
+		let more = frame.header.has_more_payload();
+		self.partial.push((frame.header, frame.payload));
+
+		if !more {
+			self.ready.extend(self.partial.drain(..));
+		}
+	}
+}
+
+impl Decoder for Codec {
+	type Item = (packet::Header, Bytes);
+	type Error = io::Error;
+
+	fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(packet::Header, Bytes)>, io::Error> {
+		loop {
+			if let Some(fragment) = self.ready.pop_front() {
+				return Ok(Some(fragment));
+			}
+
+			let end = match buf.iter().position(|&byte| byte == DELIMITER) {
+				Some(end) =>
+					end,
+
+				None => {
+					if buf.len() > MAX_FRAME {
+						buf.clear();
+						self.dropped += 1;
+					}
+
+					return Ok(None);
+				}
+			};
+
+			let frame = buf.split_to(end + 1);
+
+			// Consecutive delimiters are just noise.
+			if end == 0 {
+				continue;
+			}
+
+			match unstuff(&frame[.. end]).and_then(|frame| self.check(frame)) {
+				Some(frame) =>
+					self.reassemble(frame),
+
+				None =>
+					self.dropped += 1,
+			}
+		}
+	}
+}
+
+impl Encoder for Codec {
+	type Item = (packet::Header, Bytes);
+	type Error = io::Error;
+
+	fn encode(&mut self, (header, payload): (packet::Header, Bytes), buf: &mut BytesMut) -> Result<(), io::Error> {
+		let mut frame = BytesMut::with_capacity(PREFIX + 4 + payload.len() + 4);
+		frame.put_u8(self.next);
+		frame.put_u8(if self.sending { 0 } else { FIRST });
+		header.put(&mut frame, false)?;
+		frame.put_slice(&payload);
+
+		self.next = self.next.wrapping_add(1);
+		self.sending = header.has_more_payload();
+
+		if let Some(algorithm) = self.algorithm {
+			let checksum = algorithm.checksum(&frame);
+			frame.put_u32_be(checksum);
+		}
+
+		stuff(&frame, buf);
+		buf.put_u8(DELIMITER);
+
+		Ok(())
+	}
+}
+
+/// Build the session stack over a lossy byte link, both ends must use the
+/// same checksum algorithm.
+pub fn mi<F, S>(socket: S, algorithm: Option<Algorithm>) -> Reframed<Sessions<F>>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	let packets = Reframed::<Packets<F>>::new(Framed::new(socket, Codec::new(algorithm)));
+	packets.into_sessions()
+}
@@ -1,10 +1,40 @@
-use std::{pin::Pin, marker::PhantomData};
+use std::{io, fmt, error, pin::Pin, sync::{Arc, Mutex}, time::{Duration, Instant}, marker::PhantomData, collections::HashSet};
+use bytes::Bytes;
 use futures::{stream::{Stream, StreamExt}, sink::{Sink, SinkExt}, task::{Context, Poll}};
-use tokio::{stream, future, sync::mpsc::{UnboundedSender, error::UnboundedSendError, unbounded_channel}};
-use crate::{Format, packet::{self, Packet}, message::{self, Message}};
+use tokio::{stream, future, timer, sync::mpsc::{UnboundedSender, UnboundedReceiver, error::UnboundedSendError, unbounded_channel}};
+use t1ha::T1haHashMap as HashMap;
+use crate::{Format, packet::{self, Packet}, message::{Message, Mode}, auth::Identity, event::{Event, Direction, Observer}};
+#[cfg(unix)]
+use crate::unix;
+
+/// What is known about the peer a session is with.
+#[derive(Clone, Debug, Default)]
+pub struct Peer {
+	pub(crate) identity: Option<Identity>,
+
+	#[cfg(unix)]
+	pub(crate) credentials: Option<unix::Credentials>,
+}
+
+impl Peer {
+	/// The identity the peer authenticated as, if any.
+	pub fn identity(&self) -> Option<&Identity> {
+		self.identity.as_ref()
+	}
+
+	/// The credentials of the peer process, if connected through a Unix
+	/// socket.
+	#[cfg(unix)]
+	pub fn credentials(&self) -> Option<&unix::Credentials> {
+		self.credentials.as_ref()
+	}
+}
 
 /// A full message session (i.e. bound to a cookie).
 pub struct Session<F = ()> {
+	peer: Arc<Peer>,
+	registration: Option<Registration<F>>,
+	opener: Option<Opener<F>>,
 	sender: UnboundedSender<Packet<F>>,
 	stream: Pin<Box<dyn Stream<Item = Message<F>> + Send>>,
 	sink: Pin<Box<dyn Sink<Message<F>, Error = UnboundedSendError> + Send>>,
@@ -46,41 +76,70 @@ impl<I, E> Sink<I> for NoReply<I, E> {
 impl<F: Format> Session<F> {
 	pub fn no_reply<M: Into<Message<F>>>(value: M) -> Self {
 		Self {
+			peer: Arc::default(),
+			registration: None,
+			opener: None,
 			sender: unbounded_channel().0,
 			stream: Box::pin(stream::once(future::ready(value.into()))),
 			sink: Box::pin(NoReply::<Message<F>, _>::default()),
 		}
 	}
 
-	pub fn new(cookie: u16, mut sink: impl Sink<Packet<F>> + Send + Unpin + 'static) -> Self {
+	pub fn new(cookie: u32, sink: impl Sink<Packet<F>> + Send + Unpin + 'static) -> Self {
+		Self::spawn(cookie, sink).0
+	}
+
+	/// Like `new`, also returning a sender for messages to go out after the
+	/// ones sent through the session.
+	fn spawn(cookie: u32, mut sink: impl Sink<Packet<F>> + Send + Unpin + 'static) -> (Self, UnboundedSender<Message<F>>) {
 		let (packet_tx, packet_rx) = unbounded_channel::<Packet<F>>();
 		let (input_tx, mut input_rx) = unbounded_channel::<Message<F>>();
 
 		tokio::spawn(async move {
 			while let Some(message) = input_rx.next().await : Option<Message<F>> {
-				sink.send(Packet::new(match message.mode {
-					message::Mode::NoReply =>
-						packet::Cookie::Oneshot,
-
-					message::Mode::More =>
-						packet::Cookie::Stream(cookie),
-
-					message::Mode::End =>
-						packet::Cookie::Single(cookie),
-				}, message.bytes)).await.ok();
+				sink.send(message.into_packet(cookie)).await.ok();
 			}
 		});
 
-		Self {
+		(Self {
+			peer: Arc::default(),
+			registration: None,
+			opener: None,
 			sender: packet_tx,
 			stream: Box::pin(packet_rx.map(|p| Message::<F>::from(p))),
-			sink: Box::pin(input_tx),
-		}
+			sink: Box::pin(input_tx.clone()),
+		}, input_tx)
 	}
 
 	pub fn sender(&self) -> UnboundedSender<Packet<F>> {
 		self.sender.clone()
 	}
+
+	/// What is known about the peer.
+	pub fn peer(&self) -> &Peer {
+		&self.peer
+	}
+
+	pub(crate) fn with_peer(mut self, peer: Arc<Peer>) -> Self {
+		self.peer = peer;
+		self
+	}
+
+	fn with_registration(mut self, registration: Registration<F>) -> Self {
+		self.registration = Some(registration);
+		self
+	}
+
+	/// An opener for sessions toward the same peer, if the session belongs to
+	/// a connection.
+	pub fn opener(&self) -> Option<Opener<F>> {
+		self.opener.clone()
+	}
+
+	pub(crate) fn with_opener(mut self, opener: Opener<F>) -> Self {
+		self.opener = Some(opener);
+		self
+	}
 }
 
 impl<F: Format> Stream for Session<F> {
@@ -99,7 +158,13 @@ impl<F: Format> Sink<Message<F>> for Session<F> {
 	}
 
 	fn start_send(self: Pin<&mut Self>, item: Message<F>) -> Result<(), Self::Error> {
-		Pin::new(&mut Pin::get_mut(self).sink).start_send(item)
+		let this = Pin::get_mut(self);
+
+		if let Some(registration) = this.registration.as_mut() {
+			registration.last = Some(item.mode());
+		}
+
+		Pin::new(&mut this.sink).start_send(item)
 	}
 
 	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -110,3 +175,433 @@ impl<F: Format> Sink<Message<F>> for Session<F> {
 		Pin::new(&mut Pin::get_mut(self).sink).poll_close(cx)
 	}
 }
+
+/// Which end of a connection this is, deciding the cookies it can start
+/// sessions on, so both ends never pick the same one.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Role {
+	/// The end that connected, owning odd cookies.
+	Initiator,
+
+	/// The end that accepted, owning even cookies.
+	Acceptor,
+}
+
+impl Role {
+	/// Check if the cookie is owned by this end.
+	pub fn owns(self, cookie: u32) -> bool {
+		match self {
+			Role::Initiator =>
+				cookie % 2 == 1,
+
+			Role::Acceptor =>
+				cookie % 2 == 0,
+		}
+	}
+}
+
+/// A session open on a connection.
+pub(crate) struct Channel<F> {
+	pub(crate) sender: UnboundedSender<Packet<F>>,
+	id: u64,
+
+	/// The peer sent its end.
+	incoming: bool,
+
+	/// This end sent its end.
+	outgoing: bool,
+
+	/// The peer sent anything.
+	received: bool,
+}
+
+/// The sessions open on a connection, by cookie.
+pub(crate) struct Registry<F> {
+	pub(crate) channels: HashMap<u32, Channel<F>>,
+
+	/// Cookies of the sessions dropped while the peer was still sending, they
+	/// can't be used again until the peer ends them.
+	reset: HashSet<u32>,
+
+	/// Cookies of the sessions dropped before ending, they can't be used again
+	/// until their end is on its way to the peer.
+	ending: HashSet<u32>,
+
+	/// How many of the cookies in use this end could allocate.
+	owned: usize,
+
+	/// How many packets from the peer couldn't go anywhere.
+	dropped: usize,
+
+	id: u64,
+	next: u32,
+	max: u32,
+	role: Option<Role>,
+}
+
+/// Where a packet from the peer goes.
+pub(crate) enum Route<F> {
+	/// To an open session.
+	Session(UnboundedSender<Packet<F>>),
+
+	/// To a new session.
+	New,
+
+	/// Nowhere, the session was dropped here.
+	Reset,
+
+	/// Nowhere, the peer already ended the session or doesn't own the cookie.
+	Dropped,
+}
+
+impl<F> Registry<F> {
+	pub(crate) fn new(role: Option<Role>) -> Self {
+		let mut registry = Self {
+			channels: HashMap::default(),
+			reset: HashSet::new(),
+			ending: HashSet::new(),
+
+			owned: 0,
+			dropped: 0,
+
+			id: 0,
+			next: 0,
+			max: packet::MAX_COOKIE,
+			role: role,
+		};
+
+		registry.next = registry.first();
+		registry
+	}
+
+	fn first(&self) -> u32 {
+		match self.role {
+			Some(Role::Acceptor) => 2,
+			_ => 1,
+		}
+	}
+
+	/// Check if this end could allocate the cookie.
+	fn owns(&self, cookie: u32) -> bool {
+		self.role.map_or(true, |role| role.owns(cookie))
+	}
+
+	/// Note a cookie is no longer in use.
+	fn release(&mut self, cookie: u32) {
+		if self.owns(cookie) {
+			self.owned -= 1;
+		}
+	}
+
+	/// Find a cookie this end owns that no open session is using.
+	fn allocate(&mut self) -> Option<u32> {
+		let step = if self.role.is_some() { 2 } else { 1 };
+
+		// Don't bother looking when every cookie is in use.
+		if self.owned >= ((self.max - self.first()) / step + 1) as usize {
+			return None;
+		}
+
+		for _ in 0 ..= self.max / step {
+			let cookie = self.next;
+			self.next = if cookie + step > self.max { self.first() } else { cookie + step };
+
+			if !self.channels.contains_key(&cookie) && !self.reset.contains(&cookie) && !self.ending.contains(&cookie) {
+				return Some(cookie);
+			}
+		}
+
+		None
+	}
+
+	/// Route the packets on the cookie to the sender, returning the id to
+	/// forget it with.
+	fn insert(&mut self, cookie: u32, sender: UnboundedSender<Packet<F>>) -> u64 {
+		if self.owns(cookie) {
+			self.owned += 1;
+		}
+
+		self.id += 1;
+		self.channels.insert(cookie, Channel { sender, id: self.id, incoming: false, outgoing: false, received: false });
+
+		self.id
+	}
+
+	/// Note the peer ended the session, returning the sender for it if any.
+	fn end_incoming(&mut self, cookie: u32) -> Option<UnboundedSender<Packet<F>>> {
+		self.channels.get_mut(&cookie)?.incoming = true;
+		self.forget_ended(cookie)
+	}
+
+	/// Note this end ended the session.
+	pub(crate) fn end_outgoing(&mut self, cookie: u32) {
+		if let Some(channel) = self.channels.get_mut(&cookie) {
+			channel.outgoing = true;
+			self.forget_ended(cookie);
+		}
+		// The end of a dropped session is out, so the cookie is free unless the
+		// peer is still sending.
+		else if self.ending.remove(&cookie) && !self.reset.contains(&cookie) {
+			self.release(cookie);
+		}
+	}
+
+	/// Forget the session once both ends are done, returning the sender for
+	/// it.
+	fn forget_ended(&mut self, cookie: u32) -> Option<UnboundedSender<Packet<F>>> {
+		let channel = self.channels.get(&cookie)?;
+
+		if channel.incoming && channel.outgoing {
+			let channel = self.channels.remove(&cookie);
+			self.release(cookie);
+
+			channel.map(|channel| channel.sender)
+		}
+		else {
+			Some(channel.sender.clone())
+		}
+	}
+
+	/// Find where a packet from the peer on the cookie goes, noting the end of
+	/// the session if it's the last one.
+	pub(crate) fn route(&mut self, cookie: u32, last: bool) -> Route<F> {
+		if self.reset.contains(&cookie) {
+			if last {
+				self.reset.remove(&cookie);
+
+				if !self.ending.contains(&cookie) {
+					self.release(cookie);
+				}
+			}
+
+			return Route::Reset;
+		}
+
+		match self.channels.get(&cookie).map(|channel| channel.incoming) {
+			Some(false) if last => {
+				self.receive(cookie);
+				Route::Session(self.end_incoming(cookie).unwrap())
+			}
+
+			Some(false) => {
+				self.receive(cookie);
+				Route::Session(self.channels[&cookie].sender.clone())
+			}
+
+			None if !self.ending.contains(&cookie) && !self.is_trespassing(cookie) =>
+				Route::New,
+
+			_ => {
+				self.dropped += 1;
+				Route::Dropped
+			}
+		}
+	}
+
+	/// Note the peer started the session on the cookie.
+	pub(crate) fn receive(&mut self, cookie: u32) {
+		if let Some(channel) = self.channels.get_mut(&cookie) {
+			channel.received = true;
+		}
+	}
+
+	/// Forget a session that went away given the mode of the last message it
+	/// sent, returning whether its end still has to be sent.
+	fn close(&mut self, cookie: u32, id: u64, last: Option<Mode>) -> bool {
+		match self.channels.get(&cookie) {
+			Some(channel) if channel.id == id => {
+				let channel = self.channels.remove(&cookie).unwrap();
+
+				// The peer never heard of the session, so there's nothing to end.
+				if last.is_none() && !channel.received {
+					self.release(cookie);
+					return false;
+				}
+
+				if !channel.incoming {
+					self.reset.insert(cookie);
+				}
+
+				// The end may be queued already, either way the cookie waits for it.
+				if !channel.outgoing {
+					self.ending.insert(cookie);
+				}
+				else if channel.incoming {
+					self.release(cookie);
+				}
+
+				!channel.outgoing && match last { Some(Mode::End) => false, _ => true }
+			}
+
+			_ =>
+				false,
+		}
+	}
+
+	/// Forget every session, returning the cookies of the ones still open.
+	pub(crate) fn clear(&mut self) -> Vec<u32> {
+		self.reset.clear();
+		self.ending.clear();
+		self.owned = 0;
+
+		self.channels.drain().map(|(cookie, _)| cookie).collect()
+	}
+
+	/// Check if the peer started a session on a cookie it doesn't own.
+	fn is_trespassing(&self, cookie: u32) -> bool {
+		self.role.map_or(false, |role| role.owns(cookie))
+	}
+}
+
+/// Forgets a session once it is dropped, ending it if the peer heard of it.
+struct Registration<F> {
+	registry: Arc<Mutex<Registry<F>>>,
+	observer: Observer,
+	cookie: u32,
+	id: u64,
+
+	/// The mode of the last message sent through the session.
+	last: Option<Mode>,
+
+	/// The end, queued after whatever the session still has to send.
+	end: Option<(UnboundedSender<Message<F>>, Message<F>)>,
+}
+
+impl<F> Drop for Registration<F> {
+	fn drop(&mut self) {
+		let (end, reset) = {
+			let mut registry = self.registry.lock().unwrap();
+			let end = registry.close(self.cookie, self.id, self.last);
+
+			(end, registry.reset.contains(&self.cookie))
+		};
+
+		if reset {
+			self.observer.emit(Event::Reset { cookie: self.cookie });
+		}
+
+		if let (true, Some((mut input, message))) = (end, self.end.take()) {
+			input.try_send(message).ok();
+		}
+	}
+}
+
+/// Every cookie is used by an open session.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Exhausted;
+
+impl fmt::Display for Exhausted {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "every cookie is in use")
+	}
+}
+
+impl error::Error for Exhausted { }
+
+impl Exhausted {
+	/// Check if an error is caused by running out of cookies.
+	pub fn find(error: &io::Error) -> Option<&Exhausted> {
+		error.get_ref().and_then(|error| error.downcast_ref::<Exhausted>())
+	}
+}
+
+/// Initial delay before trying to open a session again.
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Maximum delay before trying to open a session again.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Opens sessions on a connection, replies from the peer are routed to the
+/// `Session` instead of coming out of the connection stream.
+pub struct Opener<F = ()> {
+	registry: Arc<Mutex<Registry<F>>>,
+	sink: UnboundedSender<Packet<F>>,
+	observer: Observer,
+}
+
+impl<F> Clone for Opener<F> {
+	fn clone(&self) -> Self {
+		Self {
+			registry: self.registry.clone(),
+			sink: self.sink.clone(),
+			observer: self.observer.clone(),
+		}
+	}
+}
+
+impl<F: Format> Opener<F> {
+	pub(crate) fn new(registry: Arc<Mutex<Registry<F>>>, sink: UnboundedSender<Packet<F>>, observer: Observer) -> Self {
+		Self { registry, sink, observer }
+	}
+
+	/// Open a session on an unused cookie, failing with `Exhausted` if there
+	/// is none.
+	pub fn open(&self) -> Result<Session<F>, io::Error> {
+		let mut registry = self.registry.lock().unwrap();
+		let cookie = registry.allocate()
+			.ok_or_else(|| io::Error::new(io::ErrorKind::Other, Exhausted))?;
+
+		let session = self.start(&mut registry, cookie);
+		self.observer.emit(Event::Created { cookie, direction: Direction::Outgoing });
+
+		Ok(session)
+	}
+
+	/// Start a session on the cookie, forgotten once it is dropped or both
+	/// ends are done.
+	pub(crate) fn start(&self, registry: &mut Registry<F>, cookie: u32) -> Session<F> {
+		let (session, input) = Session::spawn(cookie, self.sink.clone());
+		let id = registry.insert(cookie, session.sender());
+
+		session.with_registration(Registration {
+			registry: self.registry.clone(),
+			observer: self.observer.clone(),
+			cookie: cookie,
+			id: id,
+
+			last: None,
+			end: Some((input, Message::new(Mode::End, Bytes::new()))),
+		}).with_opener(self.clone())
+	}
+
+	/// Open a session, backing off exponentially while every cookie is in
+	/// use.
+	pub async fn acquire(&self) -> Session<F> {
+		let mut backoff = MIN_BACKOFF;
+
+		loop {
+			if let Ok(session) = self.open() {
+				return session;
+			}
+
+			timer::delay(Instant::now() + backoff).await;
+			backoff = (backoff * 2).min(MAX_BACKOFF);
+		}
+	}
+
+	/// The number of sessions currently open, a session is open until it is
+	/// dropped or both ends are done.
+	pub fn sessions(&self) -> usize {
+		self.registry.lock().unwrap().channels.len()
+	}
+
+	/// The number of packets from the peer that were dropped, because they
+	/// came after the peer ended their session or on a cookie it doesn't own.
+	pub fn dropped(&self) -> usize {
+		self.registry.lock().unwrap().dropped
+	}
+
+	/// Subscribe to the lifecycle events of the connection.
+	pub fn events(&self) -> UnboundedReceiver<Event> {
+		self.observer.subscribe()
+	}
+
+	pub(crate) fn observer(&self) -> &Observer {
+		&self.observer
+	}
+
+	/// Allocate cookies up to `MAX_WIDE_COOKIE`, once wide headers are in use.
+	pub(crate) fn widen(&self) {
+		self.registry.lock().unwrap().max = packet::MAX_WIDE_COOKIE;
+	}
+}
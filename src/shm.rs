@@ -0,0 +1,533 @@
+//! Shared memory transport for peers on the same host.
+//!
+//! Each direction is a single producer single consumer byte ring in a memfd,
+//! with an eventfd the writer signals when there is data and one the reader
+//! signals when there is space. One peer `create`s the rings and sends the
+//! descriptors to the other, for example attached to a `Message` over
+//! `unix::mi_with_fds`, which then `open`s them.
+//!
+//! The rings are sealed so the peer can't resize them, and the positions it
+//! shares are validated, so a misbehaving peer can only cause errors.
+//!
+//! `Fragments` exchanges header and payload straight with the rings, in place
+//! of `Framed` and its intermediate buffers.
+
+use std::{io, mem, ptr, pin::Pin, ffi::CStr, task::{Context, Poll}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}, collections::VecDeque};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{ready, stream::Stream, sink::Sink};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::util::PollEvented};
+use crate::{Format, Reframed, Packets, Sessions, packet, unix::Fd};
+
+/// Default size of each ring.
+pub const CAPACITY: usize = 1024 * 1024;
+
+#[repr(C, align(64))]
+struct Padded<T>(T);
+
+/// The shared state at the start of a ring, every field on its own cache
+/// line.
+#[repr(C)]
+struct Control {
+	/// Total number of bytes written.
+	head: Padded<AtomicUsize>,
+
+	/// Total number of bytes read.
+	tail: Padded<AtomicUsize>,
+
+	/// Either peer is done with the ring.
+	closed: Padded<AtomicBool>,
+}
+
+/// Offset of the data within a ring.
+const DATA: usize = mem::size_of::<Control>();
+
+/// Seals every ring must have, so its size can't change under the mapping.
+const SEALS: libc::c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+
+fn corrupted() -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, "shared memory ring corrupted")
+}
+
+fn check(result: libc::c_int) -> Result<libc::c_int, io::Error> {
+	if result < 0 {
+		Err(io::Error::last_os_error())
+	}
+	else {
+		Ok(result)
+	}
+}
+
+/// A non-blocking eventfd.
+struct EventFd(Fd);
+
+impl EventFd {
+	fn new() -> Result<Self, io::Error> {
+		let fd = check(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+		Ok(EventFd(unsafe { Fd::from_raw_fd(fd) }))
+	}
+
+	fn notify(&self) -> Result<(), io::Error> {
+		let value = 1u64;
+		let result = unsafe { libc::write(self.0.as_raw_fd(), &value as *const u64 as *const libc::c_void, 8) };
+
+		match result {
+			// The counter is saturated, the reader will wake up anyway.
+			-1 if io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock =>
+				Ok(()),
+
+			-1 =>
+				Err(io::Error::last_os_error()),
+
+			_ =>
+				Ok(()),
+		}
+	}
+
+	fn drain(&self) -> Result<(), io::Error> {
+		let mut value = 0u64;
+
+		if unsafe { libc::read(self.0.as_raw_fd(), &mut value as *mut u64 as *mut libc::c_void, 8) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(())
+	}
+}
+
+impl mio::Evented for EventFd {
+	fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> Result<(), io::Error> {
+		mio::unix::EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+	}
+
+	fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> Result<(), io::Error> {
+		mio::unix::EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+	}
+
+	fn deregister(&self, poll: &mio::Poll) -> Result<(), io::Error> {
+		mio::unix::EventedFd(&self.0.as_raw_fd()).deregister(poll)
+	}
+}
+
+/// A ring mapped in memory.
+struct Ring {
+	memory: *mut u8,
+	capacity: usize,
+}
+
+unsafe impl Send for Ring { }
+unsafe impl Sync for Ring { }
+
+impl Ring {
+	fn create(capacity: usize) -> Result<(Self, Fd), io::Error> {
+		let name = CStr::from_bytes_with_nul(b"protociolla\0").unwrap();
+		let fd = check(unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) })?;
+		let fd = unsafe { Fd::from_raw_fd(fd) };
+
+		check(unsafe { libc::ftruncate(fd.as_raw_fd(), (DATA + capacity) as libc::off_t) })?;
+		check(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_ADD_SEALS, SEALS) })?;
+
+		Ok((Self::map(&fd)?, fd))
+	}
+
+	fn map(fd: &Fd) -> Result<Self, io::Error> {
+		let seals = check(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GET_SEALS) })?;
+		if seals & SEALS != SEALS {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "shared memory not sealed"));
+		}
+
+		let mut stat: libc::stat = unsafe { mem::zeroed() };
+		check(unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) })?;
+
+		let length = stat.st_size as usize;
+		if length <= DATA {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "shared memory too small"));
+		}
+
+		let memory = unsafe {
+			libc::mmap(ptr::null_mut(), length, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd.as_raw_fd(), 0)
+		};
+
+		if memory == libc::MAP_FAILED {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(Ring {
+			memory: memory as *mut u8,
+			capacity: length - DATA,
+		})
+	}
+
+	fn control(&self) -> &Control {
+		unsafe { &*(self.memory as *const Control) }
+	}
+
+	fn data(&self) -> *mut u8 {
+		unsafe { self.memory.add(DATA) }
+	}
+
+	fn is_closed(&self) -> bool {
+		self.control().closed.0.load(Ordering::Acquire)
+	}
+
+	fn close(&self) {
+		self.control().closed.0.store(true, Ordering::Release);
+	}
+
+	/// The number of bytes in the ring, failing if the positions can't be
+	/// right.
+	fn used(&self, head: usize, tail: usize) -> Result<usize, io::Error> {
+		let used = head.wrapping_sub(tail);
+
+		if used > self.capacity {
+			return Err(corrupted());
+		}
+
+		Ok(used)
+	}
+
+	/// Copy as much of the buffer as fits.
+	fn write(&self, buf: &[u8]) -> Result<usize, io::Error> {
+		let control = self.control();
+		let head = control.head.0.load(Ordering::Relaxed);
+		let tail = control.tail.0.load(Ordering::Acquire);
+
+		let length = buf.len().min(self.capacity - self.used(head, tail)?);
+		let offset = head % self.capacity;
+		let first = length.min(self.capacity - offset);
+
+		unsafe {
+			ptr::copy_nonoverlapping(buf.as_ptr(), self.data().add(offset), first);
+			ptr::copy_nonoverlapping(buf.as_ptr().add(first), self.data(), length - first);
+		}
+
+		control.head.0.store(head.wrapping_add(length), Ordering::Release);
+		Ok(length)
+	}
+
+	/// Copy as much as is available into the buffer.
+	fn read(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		let control = self.control();
+		let tail = control.tail.0.load(Ordering::Relaxed);
+		let head = control.head.0.load(Ordering::Acquire);
+
+		let length = buf.len().min(self.used(head, tail)?);
+		let offset = tail % self.capacity;
+		let first = length.min(self.capacity - offset);
+
+		unsafe {
+			ptr::copy_nonoverlapping(self.data().add(offset), buf.as_mut_ptr(), first);
+			ptr::copy_nonoverlapping(self.data(), buf.as_mut_ptr().add(first), length - first);
+		}
+
+		control.tail.0.store(tail.wrapping_add(length), Ordering::Release);
+		Ok(length)
+	}
+}
+
+impl Drop for Ring {
+	fn drop(&mut self) {
+		unsafe {
+			libc::munmap(self.memory as *mut libc::c_void, DATA + self.capacity);
+		}
+	}
+}
+
+/// An `AsyncRead + AsyncWrite` over a pair of shared memory rings.
+pub struct Shared {
+	tx: Ring,
+	rx: Ring,
+
+	/// Signaled by the peer when `rx` has data.
+	readable: PollEvented<EventFd>,
+
+	/// Signaled by the peer when `tx` has space.
+	writable: PollEvented<EventFd>,
+
+	/// Signaled when `tx` has data.
+	data: EventFd,
+
+	/// Signaled when `rx` has space.
+	space: EventFd,
+}
+
+impl Shared {
+	/// Create the rings, returning the descriptors to send to the peer.
+	pub fn create(capacity: usize) -> Result<(Self, Vec<Fd>), io::Error> {
+		let (tx, tx_memory) = Ring::create(capacity)?;
+		let (rx, rx_memory) = Ring::create(capacity)?;
+		let (tx_data, tx_space) = (EventFd::new()?, EventFd::new()?);
+		let (rx_data, rx_space) = (EventFd::new()?, EventFd::new()?);
+
+		let fds = vec![
+			tx_memory, tx_data.0.try_clone()?, tx_space.0.try_clone()?,
+			rx_memory, rx_data.0.try_clone()?, rx_space.0.try_clone()?,
+		];
+
+		Ok((Self::new(tx, rx, tx_data, tx_space, rx_data, rx_space), fds))
+	}
+
+	/// Open the rings created by the peer.
+	pub fn open(fds: Vec<Fd>) -> Result<Self, io::Error> {
+		if fds.len() != 6 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "expected 6 file descriptors"));
+		}
+
+		let mut fds = fds.into_iter();
+		let mut next = || fds.next().unwrap();
+
+		// What the creator writes we read, and the other way around.
+		let (rx_memory, rx_data, rx_space) = (next(), EventFd(next()), EventFd(next()));
+		let (tx_memory, tx_data, tx_space) = (next(), EventFd(next()), EventFd(next()));
+
+		Ok(Self::new(Ring::map(&tx_memory)?, Ring::map(&rx_memory)?, tx_data, tx_space, rx_data, rx_space))
+	}
+
+	fn new(tx: Ring, rx: Ring, tx_data: EventFd, tx_space: EventFd, rx_data: EventFd, rx_space: EventFd) -> Self {
+		Self {
+			tx, rx,
+
+			readable: PollEvented::new(rx_data),
+			writable: PollEvented::new(tx_space),
+
+			data: tx_data,
+			space: rx_space,
+		}
+	}
+}
+
+impl AsyncRead for Shared {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+
+		if buf.is_empty() {
+			return Poll::Ready(Ok(0));
+		}
+
+		loop {
+			let read = this.rx.read(buf)?;
+
+			if read > 0 {
+				this.space.notify()?;
+				return Poll::Ready(Ok(read));
+			}
+
+			if this.rx.is_closed() {
+				return Poll::Ready(Ok(0));
+			}
+
+			ready!(this.readable.poll_read_ready(cx, mio::Ready::readable()))?;
+
+			match this.readable.get_ref().drain() {
+				Ok(()) =>
+					continue,
+
+				Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+					this.readable.clear_read_ready(cx, mio::Ready::readable())?;
+					return Poll::Pending;
+				}
+
+				Err(error) =>
+					return Poll::Ready(Err(error)),
+			}
+		}
+	}
+}
+
+impl AsyncWrite for Shared {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+
+		if buf.is_empty() {
+			return Poll::Ready(Ok(0));
+		}
+
+		loop {
+			if this.tx.is_closed() {
+				return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+			}
+
+			let written = this.tx.write(buf)?;
+
+			if written > 0 {
+				this.data.notify()?;
+				return Poll::Ready(Ok(written));
+			}
+
+			ready!(this.writable.poll_read_ready(cx, mio::Ready::readable()))?;
+
+			match this.writable.get_ref().drain() {
+				Ok(()) =>
+					continue,
+
+				Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+					this.writable.clear_read_ready(cx, mio::Ready::readable())?;
+					return Poll::Pending;
+				}
+
+				Err(error) =>
+					return Poll::Ready(Err(error)),
+			}
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		self.tx.close();
+		Poll::Ready(self.data.notify())
+	}
+}
+
+impl Drop for Shared {
+	fn drop(&mut self) {
+		self.tx.close();
+		self.rx.close();
+
+		self.data.notify().ok();
+		self.space.notify().ok();
+	}
+}
+
+/// Maximum number of outgoing buffers queued before writing them out.
+const MAX_QUEUE: usize = 64;
+
+/// A `Stream + Sink` of header and payload over shared memory, copying them
+/// straight between the buffers and the rings.
+pub struct Fragments {
+	shared: Shared,
+
+	/// The header of the incoming fragment, and how much of it was read.
+	header: [u8; 4],
+	filled: usize,
+
+	/// The incoming payload, and how much of it was read.
+	incoming: Option<(packet::Header, BytesMut, usize)>,
+
+	outgoing: VecDeque<Bytes>,
+}
+
+impl Fragments {
+	/// Exchange fragments over the rings.
+	pub fn new(shared: Shared) -> Self {
+		Self {
+			shared: shared,
+
+			header: [0; 4],
+			filled: 0,
+			incoming: None,
+
+			outgoing: VecDeque::new(),
+		}
+	}
+
+	fn poll_write_queue(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		while let Some(front) = self.outgoing.front_mut() {
+			let written = ready!(Pin::new(&mut self.shared).poll_write(cx, front))?;
+
+			if written == 0 {
+				return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+			}
+
+			front.advance(written);
+
+			if front.is_empty() {
+				self.outgoing.pop_front();
+			}
+		}
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl Stream for Fragments {
+	type Item = Result<(packet::Header, Bytes), io::Error>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+
+		loop {
+			if let Some((_, payload, read)) = this.incoming.as_mut() {
+				while *read < payload.len() {
+					match ready!(Pin::new(&mut this.shared).poll_read(cx, &mut payload[*read ..]))? {
+						0 =>
+							return Poll::Ready(Some(Err(io::ErrorKind::UnexpectedEof.into()))),
+
+						length =>
+							*read += length,
+					}
+				}
+
+				let (header, payload, _) = this.incoming.take().unwrap();
+				return Poll::Ready(Some(Ok((header, payload.freeze()))));
+			}
+
+			while this.filled < 4 {
+				match ready!(Pin::new(&mut this.shared).poll_read(cx, &mut this.header[this.filled ..]))? {
+					0 if this.filled == 0 =>
+						return Poll::Ready(None),
+
+					0 =>
+						return Poll::Ready(Some(Err(io::ErrorKind::UnexpectedEof.into()))),
+
+					length =>
+						this.filled += length,
+				}
+			}
+
+			this.filled = 0;
+
+			let header = packet::Header::read(&this.header);
+			let payload = BytesMut::from(vec![0; header.length()]);
+			this.incoming = Some((header, payload, 0));
+		}
+	}
+}
+
+impl Sink<(packet::Header, Bytes)> for Fragments {
+	type Error = io::Error;
+
+	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.get_mut();
+
+		if this.outgoing.len() >= MAX_QUEUE {
+			ready!(this.poll_write_queue(cx))?;
+		}
+
+		Poll::Ready(Ok(()))
+	}
+
+	fn start_send(self: Pin<&mut Self>, (header, payload): (packet::Header, Bytes)) -> Result<(), Self::Error> {
+		let this = self.get_mut();
+
+		let mut bytes = BytesMut::with_capacity(packet::SIZE);
+		header.put(&mut bytes, false)?;
+
+		this.outgoing.push_back(bytes.freeze());
+
+		if !payload.is_empty() {
+			this.outgoing.push_back(payload);
+		}
+
+		Ok(())
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.get_mut().poll_write_queue(cx)
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.get_mut();
+
+		ready!(this.poll_write_queue(cx))?;
+		Pin::new(&mut this.shared).poll_shutdown(cx)
+	}
+}
+
+/// Build the session stack over shared memory.
+pub fn mi<F: Format>(shared: Shared) -> Reframed<Sessions<F>> {
+	let packets = Reframed::<Packets<F>>::new(Fragments::new(shared));
+	packets.into_sessions()
+}
@@ -0,0 +1,59 @@
+//! TLS transport through rustls.
+//!
+//! The handshake negotiates the `ALPN` identifier for this version of the
+//! protocol, peers not speaking it are refused.
+
+use std::{io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsConnector, TlsAcceptor, rustls::{ClientConfig, ServerConfig, Session}, webpki::DNSNameRef};
+use crate::{Format, Reframed, Sessions, Role};
+
+/// The ALPN identifier for this version of the protocol.
+pub const ALPN: &[u8] = b"protociolla/0.1";
+
+/// Prepare a client configuration, advertising the protocol through ALPN.
+pub fn client(mut config: ClientConfig) -> Arc<ClientConfig> {
+	config.set_protocols(&[ALPN.to_vec()]);
+	Arc::new(config)
+}
+
+/// Prepare a server configuration, advertising the protocol through ALPN.
+pub fn server(mut config: ServerConfig) -> Arc<ServerConfig> {
+	config.set_protocols(&[ALPN.to_vec()]);
+	Arc::new(config)
+}
+
+fn check(protocol: Option<&[u8]>) -> Result<(), io::Error> {
+	if protocol != Some(ALPN) {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "peer does not speak protociolla"));
+	}
+
+	Ok(())
+}
+
+/// Perform the TLS handshake as a client, then build the session stack over
+/// the secured stream.
+pub async fn connect<F, S>(socket: S, domain: &str, config: Arc<ClientConfig>) -> Result<Reframed<Sessions<F>>, io::Error>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	let domain = DNSNameRef::try_from_ascii_str(domain)
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid domain name"))?;
+
+	let stream = TlsConnector::from(config).connect(domain, socket).await?;
+	check(stream.get_ref().1.get_alpn_protocol())?;
+
+	Ok(crate::mi_as(stream, Role::Initiator))
+}
+
+/// Perform the TLS handshake as a server, then build the session stack over
+/// the secured stream.
+pub async fn accept<F, S>(socket: S, config: Arc<ServerConfig>) -> Result<Reframed<Sessions<F>>, io::Error>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	let stream = TlsAcceptor::from(config).accept(socket).await?;
+	check(stream.get_ref().1.get_alpn_protocol())?;
+
+	Ok(crate::mi_as(stream, Role::Acceptor))
+}
@@ -0,0 +1,389 @@
+//! Datagram transport between two peers.
+//!
+//! Every fragment is split into datagrams small enough to avoid IP
+//! fragmentation. Single datagram `Cookie::Oneshot` packets are sent fire and
+//! forget, everything else is sequenced, acknowledged and retransmitted until
+//! the peer receives it, and delivered in order.
+
+use std::{io, pin::Pin, net::SocketAddr, sync::{Arc, Mutex}, task::{Context, Poll}, time::{Duration, Instant}, collections::{BTreeMap, VecDeque}};
+use bytes::{BufMut, Bytes, BytesMut, ByteOrder, BigEndian};
+use futures::{select, pin_mut, future::{self, FutureExt}, stream::{Stream, StreamExt}, sink::{Sink, SinkExt}};
+use tokio::{net::{UdpSocket, udp::{RecvHalf, SendHalf}}, timer::Interval, sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender, UnboundedReceiver}};
+use crate::{Format, Reframed, Packets, Sessions, packet};
+
+/// The datagram is not sequenced.
+const UNRELIABLE: u8 = 0;
+
+/// The datagram is sequenced and must be acknowledged.
+const RELIABLE: u8 = 1;
+
+/// The datagram acknowledges a sequenced one.
+const ACK: u8 = 2;
+
+/// Length of the datagram header (kind and sequence number).
+const HEADER: usize = 5;
+
+/// Maximum payload carried by a single datagram.
+const MAX_PAYLOAD: usize = 1200;
+
+/// Maximum number of unacknowledged datagrams.
+const WINDOW: usize = 256;
+
+/// Time after which an unacknowledged datagram is sent again.
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Number of retransmissions after which the peer is considered gone.
+const RETRIES: usize = 25;
+
+struct Unacked {
+	datagram: Bytes,
+	sent: Instant,
+	retries: usize,
+}
+
+#[derive(Default)]
+struct Reliability {
+	next: u32,
+	unacked: BTreeMap<u32, Unacked>,
+
+	/// Sequenced fragments received but not delivered yet, at most `WINDOW`
+	/// past the next one to deliver.
+	expected: u32,
+	pending: BTreeMap<u32, (packet::Header, Bytes)>,
+
+	/// A sequenced packet has only been partially delivered, so unsequenced
+	/// fragments have to wait for it to end.
+	partial: bool,
+	deferred: VecDeque<(packet::Header, Bytes)>,
+}
+
+impl Reliability {
+	/// The next fragment that can be delivered, if any.
+	fn ready(&mut self) -> Option<(packet::Header, Bytes)> {
+		let expected = self.expected;
+
+		if let Some(fragment) = self.pending.remove(&expected) {
+			self.expected = expected.wrapping_add(1);
+			self.partial = fragment.0.has_more_payload();
+
+			Some(fragment)
+		}
+		else if !self.partial {
+			self.deferred.pop_front()
+		}
+		else {
+			None
+		}
+	}
+}
+
+fn datagram(kind: u8, sequence: u32, header: Option<packet::Header>, payload: &[u8]) -> Bytes {
+	let mut bytes = BytesMut::with_capacity(HEADER + 4 + payload.len());
+	bytes.put_u8(kind);
+	bytes.put_u32_be(sequence);
+
+	if let Some(header) = header {
+		// The cookie was checked when the fragment was sent.
+		let mut raw = [0; 4];
+		header.write(&mut raw).unwrap();
+		bytes.put_slice(&raw);
+	}
+
+	bytes.put_slice(payload);
+	bytes.freeze()
+}
+
+/// A `Stream + Sink` of header and payload over a connected `UdpSocket`.
+pub struct Datagrams {
+	stream: Receiver<Result<(packet::Header, Bytes), io::Error>>,
+	sink: Sender<(packet::Header, Bytes)>,
+}
+
+impl Datagrams {
+	/// Exchange fragments with the given peer.
+	pub async fn connect(socket: UdpSocket, peer: SocketAddr) -> Result<Self, io::Error> {
+		socket.connect(peer).await?;
+
+		let (receiver, sender) = socket.split();
+		let state = Arc::new(Mutex::new(Reliability::default()));
+
+		let (stream_tx, stream_rx) = channel(16);
+		let (sink_tx, sink_rx) = channel(16);
+		let (ack_tx, ack_rx) = unbounded_channel();
+		let (ready_tx, ready_rx) = channel(1);
+
+		tokio::spawn(receive(receiver, state.clone(), stream_tx.clone(), ack_tx, ready_tx));
+		tokio::spawn(deliver(state.clone(), stream_tx.clone(), ready_rx));
+		tokio::spawn(send(sender, state, stream_tx, sink_rx, ack_rx));
+
+		Ok(Self {
+			stream: stream_rx,
+			sink: sink_tx,
+		})
+	}
+}
+
+impl Stream for Datagrams {
+	type Item = Result<(packet::Header, Bytes), io::Error>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		Pin::new(&mut self.get_mut().stream).poll_next(cx)
+	}
+}
+
+impl Sink<(packet::Header, Bytes)> for Datagrams {
+	type Error = io::Error;
+
+	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Pin::new(&mut self.get_mut().sink).poll_ready(cx)
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+	}
+
+	fn start_send(self: Pin<&mut Self>, item: (packet::Header, Bytes)) -> Result<(), Self::Error> {
+		item.0.check()?;
+
+		Pin::new(&mut self.get_mut().sink).start_send(item)
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Pin::new(&mut self.get_mut().sink).poll_flush(cx)
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Pin::new(&mut self.get_mut().sink).poll_close(cx)
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+	}
+}
+
+/// Receive datagrams, handling acknowledgements right away and queueing
+/// fragments for `deliver`, so a slow consumer never delays acknowledgements.
+async fn receive(mut socket: RecvHalf, state: Arc<Mutex<Reliability>>, mut out: Sender<Result<(packet::Header, Bytes), io::Error>>, acks: UnboundedSender<u32>, mut ready: Sender<()>) {
+	let mut buffer = vec![0; HEADER + 4 + MAX_PAYLOAD];
+
+	loop {
+		let length = match socket.recv(&mut buffer).await {
+			Ok(length) => length,
+
+			Err(error) => {
+				out.send(Err(error)).await.ok();
+				return;
+			}
+		};
+
+		// Garbage is dropped, anything that matters will be retransmitted.
+		if length < HEADER {
+			continue;
+		}
+
+		let kind = buffer[0];
+		let sequence = BigEndian::read_u32(&buffer[1..]);
+
+		if kind == ACK {
+			state.lock().unwrap().unacked.remove(&sequence);
+			continue;
+		}
+
+		if length < HEADER + 4 {
+			continue;
+		}
+
+		let header = packet::Header::read(&buffer[HEADER ..]);
+		let payload = Bytes::from(&buffer[HEADER + 4 .. length]);
+
+		{
+			let mut state = state.lock().unwrap();
+
+			match kind {
+				UNRELIABLE => {
+					// Nothing guarantees these arrive anyway.
+					if state.deferred.len() < WINDOW {
+						state.deferred.push_back((header, payload));
+					}
+				}
+
+				RELIABLE => {
+					let offset = sequence.wrapping_sub(state.expected);
+
+					// Past the window it's dropped without acknowledging, and the
+					// peer retransmits it once there is room.
+					if (offset as usize) < WINDOW {
+						state.pending.insert(sequence, (header, payload));
+						acks.send(sequence).ok();
+					}
+					// Already delivered, the acknowledgement was lost.
+					else if offset >= (1 << 31) {
+						acks.send(sequence).ok();
+					}
+				}
+
+				_ => (),
+			}
+		}
+
+		match ready.try_send(()) {
+			Err(ref error) if error.is_closed() =>
+				return,
+
+			_ => (),
+		}
+	}
+}
+
+/// Deliver received fragments in order as the consumer takes them.
+async fn deliver(state: Arc<Mutex<Reliability>>, mut out: Sender<Result<(packet::Header, Bytes), io::Error>>, mut ready: Receiver<()>) {
+	while ready.next().await.is_some() {
+		loop {
+			let fragment = state.lock().unwrap().ready();
+
+			if let Some(fragment) = fragment {
+				if out.send(Ok(fragment)).await.is_err() {
+					return;
+				}
+			}
+			else {
+				break;
+			}
+		}
+	}
+}
+
+async fn send(mut socket: SendHalf, state: Arc<Mutex<Reliability>>, mut out: Sender<Result<(packet::Header, Bytes), io::Error>>, fragments: Receiver<(packet::Header, Bytes)>, acks: UnboundedReceiver<u32>) {
+	macro_rules! send {
+		($datagram:expr) => (
+			if let Err(error) = socket.send(&$datagram).await {
+				out.send(Err(error)).await.ok();
+				return;
+			}
+		);
+	}
+
+	let mut fragments = fragments.fuse();
+	let mut acks = acks.fuse();
+	let mut ticks = Interval::new_interval(TIMEOUT / 2).fuse();
+
+	// Sequenced datagrams waiting for room in the window.
+	let mut queue = VecDeque::<(packet::Header, Bytes)>::new();
+
+	loop {
+		loop {
+			let datagram = {
+				let mut state = state.lock().unwrap();
+
+				if queue.is_empty() || state.unacked.len() >= WINDOW {
+					break;
+				}
+
+				let (header, chunk) = queue.pop_front().unwrap();
+				let sequence = state.next;
+				state.next = sequence.wrapping_add(1);
+
+				let datagram = datagram(RELIABLE, sequence, Some(header), &chunk);
+				state.unacked.insert(sequence, Unacked {
+					datagram: datagram.clone(),
+					sent: Instant::now(),
+					retries: 0,
+				});
+
+				datagram
+			};
+
+			send!(datagram);
+		}
+
+		// No more fragments are taken while the window is full, acknowledgements
+		// and retransmissions have to keep going meanwhile.
+		let blocked = !queue.is_empty();
+		let fragment = async {
+			if blocked {
+				future::pending::<()>().await;
+			}
+
+			fragments.next().await
+		}.fuse();
+
+		pin_mut!(fragment);
+
+		select! {
+			fragment = fragment => {
+				let (header, payload) = if let Some(fragment) = fragment {
+					fragment
+				}
+				else {
+					return;
+				};
+
+				// Fire and forget what fits in a single datagram.
+				if header.cookie().is_none() && !header.has_more_payload() && payload.len() <= MAX_PAYLOAD {
+					send!(datagram(UNRELIABLE, 0, Some(header), &payload));
+					continue;
+				}
+
+				let cookie = header.to_cookie();
+				let mut offset = 0;
+
+				loop {
+					let chunk = payload.slice(offset, payload.len().min(offset + MAX_PAYLOAD));
+					offset += chunk.len();
+
+					let is_last = offset >= payload.len();
+					let header = if is_last && !header.has_more_payload() {
+						packet::Header::new(cookie, Some(chunk.len()))
+					}
+					else {
+						packet::Header::new(cookie, None)
+					};
+
+					queue.push_back((header, chunk));
+
+					if is_last {
+						break;
+					}
+				}
+			}
+
+			sequence = acks.next() => {
+				if let Some(sequence) = sequence {
+					send!(datagram(ACK, sequence, None, &[]));
+				}
+			}
+
+			_ = ticks.next() => {
+				let (expired, lost) = {
+					let mut state = state.lock().unwrap();
+					let now = Instant::now();
+					let mut expired = Vec::new();
+					let mut lost = false;
+
+					for unacked in state.unacked.values_mut() {
+						if now - unacked.sent >= TIMEOUT {
+							unacked.sent = now;
+							unacked.retries += 1;
+							lost |= unacked.retries > RETRIES;
+
+							expired.push(unacked.datagram.clone());
+						}
+					}
+
+					(expired, lost)
+				};
+
+				if lost {
+					out.send(Err(io::Error::new(io::ErrorKind::TimedOut, "peer stopped acknowledging"))).await.ok();
+					return;
+				}
+
+				for datagram in expired {
+					send!(datagram);
+				}
+			}
+		}
+	}
+}
+
+/// Build the session stack over datagrams exchanged with the given peer.
+pub async fn mi<F: Format>(socket: UdpSocket, peer: SocketAddr) -> Result<Reframed<Sessions<F>>, io::Error> {
+	let packets = Reframed::<Packets<F>>::new(Datagrams::connect(socket, peer).await?);
+	Ok(packets.into_sessions())
+}
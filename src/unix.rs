@@ -0,0 +1,531 @@
+//! Unix domain socket transport.
+//!
+//! Connections built with `mi_with_fds` can also carry file descriptors,
+//! which are attached to a `Message` and received along it.
+
+use std::{io, mem, ptr, path::Path, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}, marker::PhantomData, collections::VecDeque};
+use std::os::unix::{net, io::{AsRawFd, FromRawFd, IntoRawFd, RawFd}};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{ready, future, stream::StreamExt, sink::SinkExt};
+use tokio::{codec::Framed, io::{AsyncRead, AsyncWrite}, net::{UnixStream, UnixListener, util::PollEvented}};
+use crate::{Format, Reframed, Codec, Packets, Sessions, Role, Body, reframe::Source, packet::Packet, session::Peer, event::Observer};
+
+/// Credentials of the process on the other end of a socket.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Credentials {
+	/// The process ID, if the platform provides it.
+	pub pid: Option<i32>,
+
+	/// The effective user ID.
+	pub uid: u32,
+
+	/// The effective group ID.
+	pub gid: u32,
+}
+
+/// Get the credentials of the peer of a socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn credentials(socket: &impl AsRawFd) -> Result<Credentials, io::Error> {
+	let mut credentials: libc::ucred = unsafe { mem::zeroed() };
+	let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+	let result = unsafe {
+		libc::getsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+			&mut credentials as *mut libc::ucred as *mut libc::c_void, &mut length)
+	};
+
+	if result != 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(Credentials {
+		pid: Some(credentials.pid),
+		uid: credentials.uid,
+		gid: credentials.gid,
+	})
+}
+
+/// Get the credentials of the peer of a socket.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn credentials(socket: &impl AsRawFd) -> Result<Credentials, io::Error> {
+	let mut uid: libc::uid_t = unsafe { mem::zeroed() };
+	let mut gid: libc::gid_t = unsafe { mem::zeroed() };
+
+	if unsafe { libc::getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(Credentials {
+		pid: None,
+		uid: uid,
+		gid: gid,
+	})
+}
+
+/// Build the session stack over a connected socket, attaching the peer
+/// credentials to every `Session`.
+pub fn mi<F: Format>(socket: UnixStream) -> Result<Reframed<Sessions<F>>, io::Error> {
+	mi_with(socket, None)
+}
+
+fn mi_with<F: Format>(socket: UnixStream, role: Option<Role>) -> Result<Reframed<Sessions<F>>, io::Error> {
+	let peer = Arc::new(Peer {
+		credentials: Some(credentials(&socket)?),
+		.. Peer::default()
+	});
+
+	Ok(crate::mi_with(socket, role).map_stream(move |session| session.with_peer(peer.clone())))
+}
+
+/// Connect to the socket at the given path.
+pub async fn connect<F: Format>(path: impl AsRef<Path>) -> Result<Reframed<Sessions<F>>, io::Error> {
+	mi_with(UnixStream::connect(path).await?, Some(Role::Initiator))
+}
+
+/// Bind a listener at the given path.
+pub fn bind<F: Format>(path: impl AsRef<Path>) -> Result<Listener<F>, io::Error> {
+	Ok(Listener {
+		inner: UnixListener::bind(path)?,
+		_marker: PhantomData,
+	})
+}
+
+/// A listener accepting connections on a Unix socket.
+pub struct Listener<F = ()> {
+	inner: UnixListener,
+	_marker: PhantomData<F>,
+}
+
+impl<F: Format> Listener<F> {
+	/// Accept a new connection.
+	pub async fn accept(&mut self) -> Result<Reframed<Sessions<F>>, io::Error> {
+		let (socket, _) = self.inner.accept().await?;
+		mi_with(socket, Some(Role::Acceptor))
+	}
+}
+
+impl<F> AsRawFd for Listener<F> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.inner.as_raw_fd()
+	}
+}
+
+/// Maximum number of file descriptors sent along a single write.
+const MAX_FDS: usize = 253;
+
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: libc::c_int = 0;
+
+#[cfg(target_os = "linux")]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+
+#[cfg(not(target_os = "linux"))]
+const SEND_FLAGS: libc::c_int = 0;
+
+/// An owned file descriptor, closed when dropped.
+#[derive(Debug)]
+pub struct Fd(RawFd);
+
+impl Fd {
+	/// Duplicate the file descriptor.
+	pub fn try_clone(&self) -> Result<Self, io::Error> {
+		let fd = unsafe { libc::fcntl(self.0, libc::F_DUPFD_CLOEXEC, 0) };
+
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(Fd(fd))
+	}
+}
+
+impl AsRawFd for Fd {
+	fn as_raw_fd(&self) -> RawFd {
+		self.0
+	}
+}
+
+impl FromRawFd for Fd {
+	unsafe fn from_raw_fd(fd: RawFd) -> Self {
+		Fd(fd)
+	}
+}
+
+impl IntoRawFd for Fd {
+	fn into_raw_fd(self) -> RawFd {
+		let fd = self.0;
+		mem::forget(self);
+
+		fd
+	}
+}
+
+impl Drop for Fd {
+	fn drop(&mut self) {
+		unsafe {
+			libc::close(self.0);
+		}
+	}
+}
+
+/// File descriptors attached to a message, shared between clones and taken at
+/// most once.
+#[derive(Clone, Default, Debug)]
+pub struct Descriptors {
+	inner: Arc<Mutex<Vec<Fd>>>,
+}
+
+impl Descriptors {
+	pub(crate) fn extend(&self, fds: impl IntoIterator<Item = Fd>) {
+		self.inner.lock().unwrap().extend(fds);
+	}
+
+	pub(crate) fn take(&self) -> Vec<Fd> {
+		mem::replace(&mut *self.inner.lock().unwrap(), Vec::new())
+	}
+}
+
+type Queue = Arc<Mutex<VecDeque<Fd>>>;
+
+fn control(count: usize) -> Vec<u8> {
+	vec![0; unsafe { libc::CMSG_SPACE((count * mem::size_of::<libc::c_int>()) as u32) } as usize]
+}
+
+fn recv(fd: RawFd, buf: &mut [u8], fds: &mut Vec<Fd>) -> Result<usize, io::Error> {
+	let mut control = control(MAX_FDS);
+	let mut iov = libc::iovec {
+		iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+		iov_len: buf.len(),
+	};
+
+	let mut message: libc::msghdr = unsafe { mem::zeroed() };
+	message.msg_iov = &mut iov;
+	message.msg_iovlen = 1;
+	message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+	message.msg_controllen = control.len() as _;
+
+	let read = unsafe { libc::recvmsg(fd, &mut message, RECV_FLAGS) };
+
+	if read < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	let truncated = message.msg_flags & libc::MSG_CTRUNC != 0;
+
+	unsafe {
+		let mut header = libc::CMSG_FIRSTHDR(&message);
+
+		while !header.is_null() {
+			if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+				let data = libc::CMSG_DATA(header) as *const libc::c_int;
+				let count = ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / mem::size_of::<libc::c_int>();
+
+				for i in 0 .. count {
+					fds.push(Fd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+				}
+			}
+
+			header = libc::CMSG_NXTHDR(&message, header);
+		}
+	}
+
+	// Some descriptors were discarded, close the ones that did arrive rather
+	// than handing out a partial list.
+	if truncated {
+		fds.clear();
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "file descriptors truncated"));
+	}
+
+	Ok(read as usize)
+}
+
+fn send(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> Result<usize, io::Error> {
+	let mut control = control(fds.len());
+	let mut iov = libc::iovec {
+		iov_base: buf.as_ptr() as *mut libc::c_void,
+		iov_len: buf.len(),
+	};
+
+	let mut message: libc::msghdr = unsafe { mem::zeroed() };
+	message.msg_iov = &mut iov;
+	message.msg_iovlen = 1;
+
+	if !fds.is_empty() {
+		message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+		message.msg_controllen = control.len() as _;
+
+		unsafe {
+			let header = libc::CMSG_FIRSTHDR(&message);
+			(*header).cmsg_level = libc::SOL_SOCKET;
+			(*header).cmsg_type = libc::SCM_RIGHTS;
+			(*header).cmsg_len = libc::CMSG_LEN((fds.len() * mem::size_of::<libc::c_int>()) as u32) as _;
+
+			ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(header) as *mut libc::c_int, fds.len());
+		}
+	}
+
+	let written = unsafe { libc::sendmsg(fd, &message, SEND_FLAGS) };
+
+	if written < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(written as usize)
+}
+
+/// An `AsyncRead + AsyncWrite` over a Unix socket, sending the queued file
+/// descriptors along the next write and queueing the ones received.
+pub struct Ancillary {
+	io: PollEvented<mio_uds::UnixStream>,
+
+	outgoing: Queue,
+	incoming: Queue,
+}
+
+impl Ancillary {
+	/// Wrap a connected socket.
+	pub fn new(socket: net::UnixStream) -> Result<Self, io::Error> {
+		socket.set_nonblocking(true)?;
+		Ok(Self::from_mio(mio_uds::UnixStream::from_stream(socket)?))
+	}
+
+	fn from_mio(socket: mio_uds::UnixStream) -> Self {
+		Self {
+			io: PollEvented::new(socket),
+
+			outgoing: Queue::default(),
+			incoming: Queue::default(),
+		}
+	}
+}
+
+impl AsRawFd for Ancillary {
+	fn as_raw_fd(&self) -> RawFd {
+		self.io.get_ref().as_raw_fd()
+	}
+}
+
+impl AsyncRead for Ancillary {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+		ready!(this.io.poll_read_ready(cx, mio::Ready::readable()))?;
+
+		let mut fds = Vec::new();
+
+		match recv(this.as_raw_fd(), buf, &mut fds) {
+			Ok(read) => {
+				this.incoming.lock().unwrap().extend(fds);
+				Poll::Ready(Ok(read))
+			}
+
+			Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+				this.io.clear_read_ready(cx, mio::Ready::readable())?;
+				Poll::Pending
+			}
+
+			Err(error) =>
+				Poll::Ready(Err(error)),
+		}
+	}
+}
+
+impl AsyncWrite for Ancillary {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+		let this = self.get_mut();
+		ready!(this.io.poll_write_ready(cx))?;
+
+		let mut outgoing = this.outgoing.lock().unwrap();
+		let count = outgoing.len().min(MAX_FDS);
+		let fds = outgoing.iter().take(count).map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+
+		match send(this.io.get_ref().as_raw_fd(), buf, &fds) {
+			Ok(written) => {
+				// The peer has its own copies now.
+				outgoing.drain(.. count);
+				Poll::Ready(Ok(written))
+			}
+
+			Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+				drop(outgoing);
+				this.io.clear_write_ready(cx)?;
+				Poll::Pending
+			}
+
+			Err(error) =>
+				Poll::Ready(Err(error)),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Poll::Ready(self.io.get_ref().shutdown(std::net::Shutdown::Write))
+	}
+}
+
+/// Prefix the payload with the number of attached descriptors and queue them
+/// for sending, streamed packets cannot carry descriptors.
+fn attach<F: Format>(packet: Packet<F>, outgoing: &Queue) -> Result<Packet<F>, io::Error> {
+	let fds = packet.fds.take();
+
+	if let Some(body) = packet.body {
+		if !fds.is_empty() {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "streamed packets cannot carry file descriptors"));
+		}
+
+		return Ok(Packet::streamed(packet.cookie, Body::prefixed(Bytes::from_static(&[0]), body)));
+	}
+
+	if fds.len() > 0xff {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many file descriptors"));
+	}
+
+	let mut bytes = BytesMut::with_capacity(1 + packet.bytes.len());
+	bytes.put_u8(fds.len() as u8);
+	bytes.put_slice(&packet.bytes);
+
+	outgoing.lock().unwrap().extend(fds);
+
+	Ok(Packet::new(packet.cookie, bytes.freeze()))
+}
+
+/// Strip the descriptor count from the payload and take as many received
+/// descriptors.
+fn detach<F: Format>(packet: Packet<F>, incoming: &Queue) -> Result<Packet<F>, io::Error> {
+	if let Some(body) = packet.body {
+		return Ok(Packet::streamed(packet.cookie, Body::skip(1, body)));
+	}
+
+	if packet.bytes.is_empty() {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "missing file descriptor count"));
+	}
+
+	let count = usize::from(packet.bytes[0]);
+	let mut queue = incoming.lock().unwrap();
+
+	if queue.len() < count {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "missing file descriptors"));
+	}
+
+	let result = Packet::new(packet.cookie, packet.bytes.slice_from(1));
+	result.fds.extend(queue.drain(.. count));
+
+	Ok(result)
+}
+
+/// Build the session stack over a connected socket, able to send and receive
+/// file descriptors along messages.
+///
+/// Both peers must be using this.
+pub fn mi_with_fds<F: Format>(socket: net::UnixStream) -> Result<Reframed<Sessions<F>>, io::Error> {
+	with_fds(Ancillary::new(socket)?, None)
+}
+
+/// Build only the packet stack over a connected socket, able to send and
+/// receive file descriptors along packets.
+pub fn packets_with_fds<F: Format>(socket: net::UnixStream) -> Result<Source<Packet<F>, Packet<F>, io::Error>, io::Error> {
+	Ok(packets(Ancillary::new(socket)?).0)
+}
+
+/// Build the packet stack over the transport, along with the observer of its
+/// reassembly events.
+fn packets<F: Format>(transport: Ancillary) -> (Source<Packet<F>, Packet<F>, io::Error>, Observer) {
+	let incoming = transport.incoming.clone();
+	let outgoing = transport.outgoing.clone();
+
+	let packets = Reframed::<Packets<F>>::new(Framed::new(transport, Codec::default()));
+	let observer = packets.handle().clone();
+
+	let (sink, stream) = packets.split();
+	let stream = stream.map(move |packet| packet.and_then(|packet| detach(packet, &incoming)));
+	let sink = sink.with(move |packet| future::ready(attach(packet, &outgoing)));
+
+	(Source::new(stream, sink), observer)
+}
+
+fn with_fds<F: Format>(transport: Ancillary, role: Option<Role>) -> Result<Reframed<Sessions<F>>, io::Error> {
+	let peer = Arc::new(Peer {
+		credentials: Some(credentials(&transport)?),
+		.. Peer::default()
+	});
+
+	let (Source { stream, sink }, observer) = packets(transport);
+	let sessions = Reframed::<Sessions<F>>::from_parts_as(stream, sink, role);
+	observer.forward(sessions.handle().observer().clone());
+
+	Ok(sessions.map_stream(move |session| session.with_peer(peer.clone())))
+}
+
+/// Connect to the socket at the given path, able to send and receive file
+/// descriptors along messages.
+pub async fn connect_with_fds<F: Format>(path: impl AsRef<Path>) -> Result<Reframed<Sessions<F>>, io::Error> {
+	let transport = Ancillary::from_mio(mio_uds::UnixStream::connect(path)?);
+
+	// The connection may still be in progress.
+	future::poll_fn(|cx| transport.io.poll_write_ready(cx)).await?;
+
+	if let Some(error) = transport.io.get_ref().take_error()? {
+		return Err(error);
+	}
+
+	with_fds(transport, Some(Role::Initiator))
+}
+
+/// Bind a listener at the given path, accepting connections able to send and
+/// receive file descriptors along messages.
+pub fn bind_with_fds<F: Format>(path: impl AsRef<Path>) -> Result<FdListener<F>, io::Error> {
+	let listener = net::UnixListener::bind(path)?;
+	listener.set_nonblocking(true)?;
+
+	Ok(FdListener {
+		io: PollEvented::new(mio_uds::UnixListener::from_listener(listener)?),
+		_marker: PhantomData,
+	})
+}
+
+/// A listener accepting connections able to send and receive file
+/// descriptors.
+pub struct FdListener<F = ()> {
+	io: PollEvented<mio_uds::UnixListener>,
+	_marker: PhantomData<F>,
+}
+
+impl<F: Format> FdListener<F> {
+	/// Accept a new connection.
+	pub async fn accept(&mut self) -> Result<Reframed<Sessions<F>>, io::Error> {
+		let socket = future::poll_fn(|cx| {
+			ready!(self.io.poll_read_ready(cx, mio::Ready::readable()))?;
+
+			match self.io.get_ref().accept() {
+				Ok(Some((socket, _))) =>
+					Poll::Ready(Ok(socket)),
+
+				Ok(None) => {
+					self.io.clear_read_ready(cx, mio::Ready::readable())?;
+					Poll::Pending
+				}
+
+				Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+					self.io.clear_read_ready(cx, mio::Ready::readable())?;
+					Poll::Pending
+				}
+
+				Err(error) =>
+					Poll::Ready(Err(error)),
+			}
+		}).await?;
+
+		with_fds(Ancillary::from_mio(socket), Some(Role::Acceptor))
+	}
+}
+
+impl<F> AsRawFd for FdListener<F> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.io.get_ref().as_raw_fd()
+	}
+}
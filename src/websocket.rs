@@ -0,0 +1,89 @@
+//! WebSocket transport, every fragment is sent as a binary message holding
+//! its header and payload.
+
+use std::io;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future, stream::StreamExt, sink::SinkExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{WebSocketStream, tungstenite::{self, Message}};
+use crate::{Format, Reframed, Packets, Sessions, Role, packet};
+
+fn error(error: tungstenite::Error) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, error)
+}
+
+fn decode(data: Vec<u8>) -> Result<(packet::Header, Bytes), io::Error> {
+	if data.len() < 4 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "fragment too short"));
+	}
+
+	let header = packet::Header::read(&data);
+	let payload = Bytes::from(data).slice_from(4);
+
+	if payload.len() != header.length() {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "fragment length mismatch"));
+	}
+
+	Ok((header, payload))
+}
+
+fn encode((header, payload): (packet::Header, Bytes)) -> Result<Message, io::Error> {
+	let mut data = BytesMut::with_capacity(4 + payload.len());
+	header.put(&mut data, false)?;
+	data.put_slice(&payload);
+
+	Ok(Message::Binary(data.to_vec()))
+}
+
+/// Build the session stack over an established WebSocket.
+pub fn mi<F, S>(socket: WebSocketStream<S>) -> Reframed<Sessions<F>>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	mi_with(socket, None)
+}
+
+fn mi_with<F, S>(socket: WebSocketStream<S>, role: Option<Role>) -> Reframed<Sessions<F>>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	let (sink, stream) = socket.split();
+
+	let stream = stream.filter_map(|message| future::ready(match message {
+		Ok(Message::Binary(data)) =>
+			Some(decode(data)),
+
+		// Control frames are handled by tungstenite, and text is not ours.
+		Ok(_) =>
+			None,
+
+		Err(err) =>
+			Some(Err(error(err))),
+	}));
+
+	let sink = sink
+		.sink_map_err(error)
+		.with(|fragment| future::ready(encode(fragment)));
+
+	let packets = Reframed::<Packets<F>>::from_parts(stream, sink);
+	let packets = packets.into_sessions_with(role);
+
+	packets
+}
+
+/// Perform the WebSocket handshake as a server, then build the session stack.
+pub async fn accept<F, S>(socket: S) -> Result<Reframed<Sessions<F>>, io::Error>
+	where F: Format,
+	      S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	Ok(mi_with(tokio_tungstenite::accept_async(socket).await.map_err(error)?, Some(Role::Acceptor)))
+}
+
+/// Connect to a WebSocket server, then build the session stack.
+pub async fn connect<F: Format>(url: &str) -> Result<Reframed<Sessions<F>>, io::Error> {
+	let url = url::Url::parse(url)
+		.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+	let (socket, _) = tokio_tungstenite::connect_async(url).await.map_err(error)?;
+	Ok(mi_with(socket, Some(Role::Initiator)))
+}
@@ -0,0 +1,154 @@
+#![cfg(unix)]
+
+use std::io;
+use bytes::Bytes;
+use futures::{future, stream::StreamExt, sink::SinkExt};
+use tokio::net::UnixStream;
+use protociolla::{Packet, Reframed, Sessions, packet::Cookie, auth::{self, Authenticator, Credentials, Identity, Verdict, bearer}};
+
+type Connection = Result<Reframed<Sessions<()>>, io::Error>;
+
+async fn authenticate(authenticator: impl Authenticator, credentials: impl Credentials) -> (Connection, Connection) {
+	let (left, right) = UnixStream::pair().unwrap();
+
+	future::join(
+		auth::accept::<(), _, _>(left, authenticator),
+		auth::connect::<(), _, _>(right, credentials)).await
+}
+
+fn validator() -> bearer::Validator<impl FnMut(&[u8]) -> Option<String> + Send> {
+	bearer::Validator::new(|token: &[u8]| {
+		if token == b"open sesame" {
+			Some("alice".into())
+		}
+		else {
+			None
+		}
+	})
+}
+
+#[tokio::test]
+async fn accept() {
+	let (server, client) = authenticate(validator(), bearer::Token(Bytes::from_static(b"open sesame"))).await;
+	let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+	client.send(Packet::new(Cookie::Single(1), Bytes::from_static(b"hello"))).await.unwrap();
+
+	let mut session = server.next().await.unwrap().unwrap();
+	assert_eq!(session.peer().identity(), Some(&Identity {
+		mechanism: bearer::MECHANISM.into(),
+		name: "alice".into(),
+	}));
+
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"hello");
+}
+
+#[tokio::test]
+async fn reject() {
+	let refuse = bearer::Validator::new(|_: &[u8]| None);
+	let (server, client) = authenticate(refuse, bearer::Token(Bytes::from_static(b"open sesame"))).await;
+
+	assert_eq!(server.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+	assert_eq!(client.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[tokio::test]
+async fn bearer_mismatch() {
+	let (server, client) = authenticate(validator(), bearer::Token(Bytes::from_static(b"open barley"))).await;
+
+	assert_eq!(server.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+	assert_eq!(client.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+}
+
+/// Credentials for a mechanism nobody supports.
+struct Unknown;
+
+impl Credentials for Unknown {
+	fn mechanism(&self) -> &str {
+		"UNKNOWN"
+	}
+
+	fn respond(&mut self, _challenge: &[u8]) -> Result<Bytes, io::Error> {
+		Ok(Bytes::new())
+	}
+}
+
+#[tokio::test]
+async fn mechanism_mismatch() {
+	let (server, client) = authenticate(validator(), Unknown).await;
+
+	assert!(server.is_err());
+	assert_eq!(client.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+/// An authenticator with a mechanism name that can't be encoded.
+struct Long(String);
+
+impl Authenticator for Long {
+	fn mechanism(&self) -> &str {
+		&self.0
+	}
+
+	fn challenge(&mut self) -> Result<Bytes, io::Error> {
+		Ok(Bytes::new())
+	}
+
+	fn verify(&mut self, _response: &[u8]) -> Result<Verdict, io::Error> {
+		unreachable!()
+	}
+}
+
+#[tokio::test]
+async fn mechanism_too_long() {
+	let (server, client) = authenticate(Long("X".repeat(256)), Unknown).await;
+
+	assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+	assert!(client.is_err());
+}
+
+#[cfg(feature = "shared-secret")]
+mod secret {
+	use super::*;
+	use protociolla::auth::secret::{self, Verifier, Secret};
+
+	fn verifier() -> Verifier<impl FnMut(&str) -> Option<Vec<u8>> + Send> {
+		Verifier::new(|name: &str| {
+			if name == "bob" {
+				Some(b"hunter2".to_vec())
+			}
+			else {
+				None
+			}
+		})
+	}
+
+	#[tokio::test]
+	async fn accept() {
+		let (server, client) = authenticate(verifier(), Secret { name: "bob".into(), secret: b"hunter2".to_vec() }).await;
+		let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+		client.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"hello"))).await.unwrap();
+
+		let session = server.next().await.unwrap().unwrap();
+		assert_eq!(session.peer().identity(), Some(&Identity {
+			mechanism: secret::MECHANISM.into(),
+			name: "bob".into(),
+		}));
+	}
+
+	#[tokio::test]
+	async fn wrong_secret() {
+		let (server, client) = authenticate(verifier(), Secret { name: "bob".into(), secret: b"hunter3".to_vec() }).await;
+
+		assert_eq!(server.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+		assert_eq!(client.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+	}
+
+	#[tokio::test]
+	async fn unknown_name() {
+		let (server, client) = authenticate(verifier(), Secret { name: "eve".into(), secret: b"hunter2".to_vec() }).await;
+
+		assert_eq!(server.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+		assert_eq!(client.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+	}
+}
@@ -0,0 +1,104 @@
+#![cfg(all(unix, any(feature = "crc32c", feature = "xxhash")))]
+
+use std::io;
+use bytes::{Bytes, BytesMut};
+use futures::{future, stream::StreamExt, sink::SinkExt};
+use tokio::{codec::{Framed, Encoder}, io::{AsyncReadExt, AsyncWriteExt}, net::UnixStream};
+use protociolla::{Reframed, Packets, Packet, packet::{Cookie, Header}, checksum::{self, Algorithm, Codec, Corrupted}};
+
+fn encoded(header: Header, payload: &'static [u8]) -> BytesMut {
+	let mut buffer = BytesMut::new();
+	Codec::new(Algorithm::ALL[0]).encode((header, Bytes::from_static(payload)), &mut buffer).unwrap();
+	buffer
+}
+
+/// Write the bytes as they arrive after transit, and read them back as
+/// packets.
+async fn receive(bytes: &[u8]) -> Result<Packet, io::Error> {
+	let (mut left, right) = UnixStream::pair().unwrap();
+	let mut right = Reframed::<Packets<()>>::new(Framed::new(right, Codec::new(Algorithm::ALL[0])));
+
+	left.write_all(bytes).await.unwrap();
+	right.next().await.unwrap()
+}
+
+fn corrupted(error: io::Error) -> Corrupted {
+	assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+	*Corrupted::find(&error).expect("not a corruption error")
+}
+
+#[tokio::test]
+async fn intact() {
+	let packet = receive(&encoded(Header::single(1, Some(5)), b"hello")).await.unwrap();
+	assert_eq!(&packet.bytes()[..], b"hello");
+}
+
+#[tokio::test]
+async fn corrupted_cookie() {
+	let mut bytes = encoded(Header::single(1, Some(5)), b"hello");
+	bytes[1] ^= 0x02;
+
+	let error = corrupted(receive(&bytes).await.unwrap_err());
+	assert_ne!(error.expected, error.actual);
+}
+
+#[tokio::test]
+async fn corrupted_length() {
+	// The length now claims far more than is sent, which must be caught
+	// without waiting for it.
+	let mut bytes = encoded(Header::single(1, Some(5)), b"hello");
+	bytes[2] ^= 0x80;
+
+	corrupted(receive(&bytes).await.unwrap_err());
+}
+
+#[tokio::test]
+async fn corrupted_payload() {
+	let mut bytes = encoded(Header::single(1, Some(5)), b"hello");
+	bytes[8] ^= 0x01;
+
+	corrupted(receive(&bytes).await.unwrap_err());
+}
+
+#[tokio::test]
+async fn corrupted_checksum() {
+	let mut bytes = encoded(Header::single(1, Some(5)), b"hello");
+	let last = bytes.len() - 1;
+	bytes[last] ^= 0x01;
+
+	corrupted(receive(&bytes).await.unwrap_err());
+}
+
+#[tokio::test]
+async fn negotiate() {
+	let (mut left, mut right) = UnixStream::pair().unwrap();
+	let (left, right) = future::join(checksum::negotiate(&mut left), checksum::negotiate(&mut right)).await;
+
+	assert_eq!(left.unwrap(), Some(Algorithm::ALL[0]));
+	assert_eq!(right.unwrap(), Some(Algorithm::ALL[0]));
+}
+
+#[tokio::test]
+async fn negotiate_nothing_in_common() {
+	let (mut left, mut right) = UnixStream::pair().unwrap();
+
+	right.write_all(&[0]).await.unwrap();
+	assert_eq!(checksum::negotiate(&mut left).await.unwrap(), None);
+
+	let mut offered = [0];
+	right.read_exact(&mut offered).await.unwrap();
+	assert_ne!(offered[0], 0);
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let (left, right) = future::join(checksum::mi::<(), _>(left), checksum::mi::<(), _>(right)).await;
+	let (mut left, mut right) = (left.unwrap(), right.unwrap());
+
+	let payload = Bytes::from(vec![7; 0xfffe * 2 + 3]);
+	left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert_eq!(message.bytes(), &payload);
+}
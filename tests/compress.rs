@@ -0,0 +1,139 @@
+#![cfg(unix)]
+
+use std::io;
+use bytes::{Bytes, BytesMut};
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::{codec::Framed, net::UnixStream};
+use protociolla::{Reframed, Codec, Packets, Packet, packet::Cookie, compress::{Algorithm, Compressed, Config}};
+
+/// Compress anything above 16 bytes, reject anything above 1KiB.
+struct Small;
+
+impl Config for Small {
+	const THRESHOLD: usize = 16;
+	const LIMIT: usize = 1024;
+
+	fn algorithms() -> &'static [Algorithm] {
+		Algorithm::ALL
+	}
+}
+
+/// A compressing peer and a raw one to look at the wire.
+fn peers() -> (Reframed<Compressed<(), Small>>, Reframed<Packets<()>>) {
+	let (left, right) = UnixStream::pair().unwrap();
+	let left = Reframed::<Packets<()>>::new(Framed::new(left, Codec::default()));
+	let left = Reframed::<Compressed<(), Small>>::new(left);
+	let right = Reframed::<Packets<()>>::new(Framed::new(right, Codec::default()));
+
+	(left, right)
+}
+
+/// Send the offer from the raw peer and wait for it to be processed.
+async fn offer(left: &mut Reframed<Compressed<(), Small>>, right: &mut Reframed<Packets<()>>, algorithms: &[Algorithm]) {
+	let mut offer = vec![0xff];
+	offer.extend(algorithms.iter().map(|a| a.id()));
+
+	right.send(Packet::new(Cookie::Oneshot, offer.into())).await.unwrap();
+	right.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"\0sync"))).await.unwrap();
+
+	assert_eq!(&left.next().await.unwrap().unwrap().bytes()[..], b"sync");
+}
+
+#[tokio::test]
+async fn offer_is_sent_first() {
+	let (_left, mut right) = peers();
+	let offer = right.next().await.unwrap().unwrap();
+
+	assert_eq!(offer.bytes()[0], 0xff);
+	assert_eq!(&offer.bytes()[1..], &Algorithm::ALL.iter().map(|a| a.id()).collect::<Vec<_>>()[..]);
+}
+
+#[tokio::test]
+async fn nothing_in_common() {
+	let (mut left, mut right) = peers();
+	right.next().await.unwrap().unwrap();
+	offer(&mut left, &mut right, &[]).await;
+
+	let payload = Bytes::from(vec![0; 4096]);
+	left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+
+	let packet = right.next().await.unwrap().unwrap();
+	assert_eq!(packet.bytes()[0], Algorithm::None.id());
+	assert_eq!(packet.bytes().slice_from(1), payload);
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "deflate"))]
+#[tokio::test]
+async fn negotiated() {
+	let (mut left, mut right) = peers();
+	right.next().await.unwrap().unwrap();
+
+	// The peer only knows the least preferred algorithm.
+	let algorithm = *Algorithm::ALL.last().unwrap();
+	offer(&mut left, &mut right, &[algorithm]).await;
+
+	let payload = Bytes::from(vec![0; 4096]);
+	left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+
+	let packet = right.next().await.unwrap().unwrap();
+	assert_eq!(packet.bytes()[0], algorithm.id());
+	assert!(packet.bytes().len() < payload.len());
+
+	let mut decompressed = BytesMut::new();
+	algorithm.decompress(&packet.bytes()[1..], &mut decompressed, Small::LIMIT * 4).unwrap();
+	assert_eq!(&decompressed[..], &payload[..]);
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "deflate"))]
+#[tokio::test]
+async fn below_threshold() {
+	let (mut left, mut right) = peers();
+	right.next().await.unwrap().unwrap();
+	offer(&mut left, &mut right, Algorithm::ALL).await;
+
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"tiny"))).await.unwrap();
+
+	let packet = right.next().await.unwrap().unwrap();
+	assert_eq!(&packet.bytes()[..], b"\0tiny");
+}
+
+#[tokio::test]
+async fn above_limit() {
+	let (mut left, mut right) = peers();
+
+	right.send(Packet::new(Cookie::Oneshot, Bytes::from(vec![0; Small::LIMIT + 2]))).await.unwrap();
+
+	let error = left.next().await.unwrap().unwrap_err();
+	assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "deflate"))]
+#[tokio::test]
+async fn decompressed_above_limit() {
+	let (mut left, mut right) = peers();
+
+	// Small on the wire, but too large once decompressed.
+	let algorithm = Algorithm::ALL[0];
+	let mut payload = BytesMut::new();
+	payload.extend_from_slice(&[algorithm.id()]);
+	algorithm.compress(&vec![0; Small::LIMIT * 4], &mut payload).unwrap();
+	assert!(payload.len() < Small::LIMIT);
+
+	right.send(Packet::new(Cookie::Oneshot, payload.freeze())).await.unwrap();
+
+	let error = left.next().await.unwrap().unwrap_err();
+	assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi_compressed::<(), _>(left);
+	let mut right = protociolla::mi_compressed::<(), _>(right);
+
+	let payload = Bytes::from(vec![42; 8192]);
+	left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert_eq!(message.bytes(), &payload);
+}
@@ -0,0 +1,71 @@
+use std::io;
+use bytes::Bytes;
+use futures::{future, stream::StreamExt, sink::SinkExt};
+use tokio::{codec::FramedRead, io::{AsyncReadExt, AsyncWriteExt}};
+use protociolla::{Format, Codec, Reframed, Sessions, Packet, Role, pipe::{self, Pipe}, packet::Cookie};
+
+const WIDE: u32 = 0x12345;
+
+async fn wide<F: Format>() -> (Reframed<Sessions<F>>, FramedRead<Pipe, Codec>) {
+	let (left, mut right) = pipe::duplex(pipe::Options::default());
+	let left = protociolla::mi_wide::<F, _>(left, Role::Initiator);
+	let right = async {
+		// Wide headers as the acceptor.
+		right.write_all(&[1]).await.unwrap();
+		right.flush().await.unwrap();
+
+		let mut theirs = [0];
+		right.read_exact(&mut theirs).await.unwrap();
+		assert_eq!(theirs[0], 1 | 2);
+
+		FramedRead::new(right, Codec::wide())
+	};
+
+	let (left, right) = future::join(left, right).await;
+	(left.unwrap(), right)
+}
+
+#[tokio::test]
+async fn wide_headers() {
+	let (mut left, mut right) = wide::<()>().await;
+
+	left.send(Packet::new(Cookie::Single(WIDE), Bytes::from_static(b"wide"))).await.unwrap();
+
+	let (header, payload) = right.next().await.unwrap().unwrap();
+	assert_eq!(header.cookie(), Some(WIDE));
+	assert_eq!(&payload[..], b"wide");
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn wide_headers_from_value() {
+	use protociolla::format::MessagePack;
+
+	let (mut left, mut right) = wide::<MessagePack>().await;
+
+	left.send(Packet::<MessagePack>::single(WIDE, &"wide").unwrap()).await.unwrap();
+
+	let (header, _) = right.next().await.unwrap().unwrap();
+	assert_eq!(header.cookie(), Some(WIDE));
+}
+
+#[tokio::test]
+async fn standard_headers() {
+	let (mut left, mut right) = protociolla::pair::<()>();
+
+	// Masking the cookie would hand the packet to another session, so the
+	// connection is aborted instead.
+	left.send(Packet::new(Cookie::Single(WIDE), Bytes::from_static(b"wide"))).await.unwrap();
+	assert!(right.next().await.is_none());
+}
+
+#[tokio::test]
+async fn same_role() {
+	let (left, right) = pipe::duplex(pipe::Options::default());
+	let (left, right) = future::join(
+		protociolla::mi_wide::<(), _>(left, Role::Initiator),
+		protociolla::mi_wide::<(), _>(right, Role::Initiator)).await;
+
+	assert_eq!(left.err().unwrap().kind(), io::ErrorKind::InvalidData);
+	assert_eq!(right.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,85 @@
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::{codec::Framed, sync::mpsc::UnboundedReceiver};
+use protociolla::{Codec, Message, Mode, Role, Event, pipe, packet::Header, event::Direction};
+
+async fn until_closed(events: &mut UnboundedReceiver<Event>) -> Vec<Event> {
+	let mut seen = Vec::new();
+	while let Some(event) = events.next().await {
+		let closed = event == Event::Closed;
+		seen.push(event);
+
+		if closed {
+			break;
+		}
+	}
+
+	seen
+}
+
+#[tokio::test]
+async fn order() {
+	let (left, right) = pipe::duplex(pipe::Options::default());
+	let mut left = protociolla::mi_as::<(), _>(left, Role::Initiator);
+	let mut events = left.events();
+
+	// The peer end is bare, so dropping it closes the connection.
+	let mut right = Framed::new(right, Codec::default());
+
+	// Oneshot packets have no events.
+	right.send((Header::oneshot(Some(3)), Bytes::from_static(b"one"))).await.unwrap();
+	let mut oneshot = left.next().await.unwrap().unwrap();
+	assert_eq!(&oneshot.next().await.unwrap().bytes()[..], b"one");
+
+	let mut session = left.open().unwrap();
+	session.send(Message::new(Mode::End, Bytes::from_static(b"ping"))).await.unwrap();
+
+	let (header, payload) = right.next().await.unwrap().unwrap();
+	assert_eq!(header.cookie(), Some(1));
+	assert!(!header.has_more_packets());
+	assert_eq!(&payload[..], b"ping");
+
+	right.send((Header::single(1, Some(4)), Bytes::from_static(b"pong"))).await.unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"pong");
+
+	drop(right);
+
+	assert_eq!(until_closed(&mut events).await, vec![
+		Event::Opened,
+		Event::Created { cookie: 1, direction: Direction::Outgoing },
+		Event::Ended { cookie: 1, direction: Direction::Outgoing },
+		Event::Ended { cookie: 1, direction: Direction::Incoming },
+		Event::Closed,
+	]);
+}
+
+#[tokio::test]
+async fn reset() {
+	let (left, right) = pipe::duplex(pipe::Options::default());
+	let mut left = protociolla::mi_as::<(), _>(left, Role::Initiator);
+	let mut events = left.events();
+
+	let mut right = Framed::new(right, Codec::default());
+
+	// Dropping the session while the peer is still sending resets it, and ends
+	// it toward the peer.
+	right.send((Header::stream(2, Some(4)), Bytes::from_static(b"more"))).await.unwrap();
+	let mut session = left.next().await.unwrap().unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"more");
+	drop(session);
+
+	let (header, payload) = right.next().await.unwrap().unwrap();
+	assert_eq!(header.cookie(), Some(2));
+	assert!(!header.has_more_packets());
+	assert!(payload.is_empty());
+
+	drop(right);
+
+	assert_eq!(until_closed(&mut events).await, vec![
+		Event::Opened,
+		Event::Created { cookie: 2, direction: Direction::Incoming },
+		Event::Reset { cookie: 2 },
+		Event::Ended { cookie: 2, direction: Direction::Outgoing },
+		Event::Closed,
+	]);
+}
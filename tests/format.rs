@@ -0,0 +1,161 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use protociolla::{Format, Packet, Message, packet::Cookie};
+
+struct Json;
+
+impl Format for Json {
+	type SerializeError = serde_json::Error;
+	type DeserializeError = serde_json::Error;
+
+	fn serialize<T: Serialize>(value: &T, buffer: &mut BytesMut) -> Result<(), Self::SerializeError> {
+		serde_json::to_writer(&mut buffer.writer(), value)
+	}
+
+	fn deserialize<T: DeserializeOwned>(buffer: &Bytes) -> Result<T, Self::DeserializeError> {
+		serde_json::from_slice(buffer)
+	}
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Ping {
+	id: u32,
+	name: String,
+}
+
+#[test]
+fn packet_with_format() {
+	let packet = Packet::<()>::new(Cookie::Stream(42), Bytes::from(&br#"{"id":1,"name":"ping"}"#[..]));
+	let packet = packet.with_format::<Json>();
+
+	match packet.cookie() {
+		Cookie::Stream(42) => (),
+		cookie => panic!("unexpected cookie: {:?}", cookie),
+	}
+
+	assert_eq!(packet.cast::<Ping>().unwrap(), Ping { id: 1, name: "ping".into() });
+
+	let packet = packet.with_format::<()>();
+	assert_eq!(&packet.bytes()[..], &br#"{"id":1,"name":"ping"}"#[..]);
+	assert!(packet.cast::<Ping>().is_err());
+}
+
+#[test]
+fn message_with_format() {
+	let message = Message::from(Packet::<()>::new(Cookie::Oneshot, Bytes::from(&br#"{"id":2,"name":"pong"}"#[..])));
+	let message = message.with_format::<Json>();
+
+	assert_eq!(message.cast::<Ping>().unwrap(), Ping { id: 2, name: "pong".into() });
+}
+
+#[test]
+fn opaque_cast_ref() {
+	let packet = Packet::<()>::new(Cookie::Oneshot, Bytes::from(&b"hello"[..]));
+
+	assert!(packet.cast_ref::<&str>().is_err());
+	assert!(packet.cast_ref::<&[u8]>().is_err());
+	assert!(Message::from(packet).cast_ref::<&str>().is_err());
+}
+
+#[cfg(feature = "msgpack")]
+mod message_pack {
+	use super::*;
+	use protociolla::format::MessagePack;
+
+	#[derive(Serialize, Deserialize, PartialEq, Debug)]
+	struct Borrowed<'a> {
+		id: u32,
+		name: &'a str,
+	}
+
+	#[test]
+	fn cast_ref_str() {
+		let packet = Packet::<MessagePack>::oneshot(&"hello").unwrap();
+		let value: &str = packet.cast_ref().unwrap();
+
+		assert_eq!(value, "hello");
+		assert_eq!(value.as_ptr(), packet.bytes()[1..].as_ptr());
+	}
+
+	#[test]
+	fn cast_ref_bytes() {
+		// bin8 with a 3 bytes payload.
+		let packet = Packet::<MessagePack>::new(Cookie::Oneshot, Bytes::from(&b"\xc4\x03abc"[..]));
+		let value: &[u8] = packet.cast_ref().unwrap();
+
+		assert_eq!(value, b"abc");
+		assert_eq!(value.as_ptr(), packet.bytes()[2..].as_ptr());
+	}
+
+	#[test]
+	fn cast_ref_struct() {
+		let message = Message::from(Packet::<MessagePack>::single(1, &Borrowed { id: 1, name: "ping" }).unwrap());
+		let value: Borrowed = message.cast_ref().unwrap();
+
+		assert_eq!(value, Borrowed { id: 1, name: "ping" });
+	}
+}
+
+#[cfg(feature = "transcode")]
+mod transcode {
+	use super::*;
+	use protociolla::format::{self, TranscodeError};
+
+	#[test]
+	fn identity() {
+		let packet = Packet::<Json>::single(7, &Ping { id: 3, name: "ping".into() }).unwrap();
+		let packet = packet.transcode::<Json>().unwrap();
+
+		match packet.cookie() {
+			Cookie::Single(7) => (),
+			cookie => panic!("unexpected cookie: {:?}", cookie),
+		}
+
+		assert_eq!(packet.cast::<Ping>().unwrap(), Ping { id: 3, name: "ping".into() });
+	}
+
+	#[cfg(feature = "msgpack")]
+	#[test]
+	fn between_formats() {
+		use protociolla::format::MessagePack;
+
+		let value = Ping { id: 4, name: "ping".into() };
+		let packet = Packet::<Json>::oneshot(&value).unwrap();
+
+		let packet = packet.transcode::<MessagePack>().unwrap();
+		assert_eq!(packet.cast::<Ping>().unwrap(), value);
+
+		let message = Message::from(packet).transcode::<Json>().unwrap();
+		assert_eq!(message.cast::<Ping>().unwrap(), value);
+	}
+
+	#[test]
+	fn from_opaque() {
+		let bytes = Bytes::from(&br#"{"id":5,"name":"ping"}"#[..]);
+
+		match format::transcode::<(), Json>(&bytes) {
+			Err(TranscodeError::Deserialize(())) => (),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+
+	#[test]
+	fn into_opaque() {
+		let bytes = Bytes::from(&br#"{"id":6,"name":"ping"}"#[..]);
+
+		match format::transcode::<Json, ()>(&bytes) {
+			Err(TranscodeError::Serialize(())) => (),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+
+	#[test]
+	fn invalid_payload() {
+		let bytes = Bytes::from(&b"not json"[..]);
+
+		match format::transcode::<Json, Json>(&bytes) {
+			Err(TranscodeError::Deserialize(_)) => (),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+}
@@ -0,0 +1,85 @@
+#![cfg(all(unix, feature = "noise"))]
+
+use std::io;
+use bytes::Bytes;
+use futures::{future, stream::StreamExt, sink::SinkExt};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UnixStream};
+use protociolla::{Packet, packet::Cookie, noise};
+
+#[tokio::test]
+async fn round_trip() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let (initiator, responder) = (noise::generate().unwrap(), noise::generate().unwrap());
+
+	let (left, right) = future::join(
+		noise::initiate(left, &initiator.private),
+		noise::respond(right, &responder.private)).await;
+
+	let (left, right) = (left.unwrap(), right.unwrap());
+	assert_eq!(left.remote_static(), Some(&responder.public[..]));
+	assert_eq!(right.remote_static(), Some(&initiator.public[..]));
+
+	let mut left = protociolla::mi::<(), _>(left);
+	let mut right = protociolla::mi::<(), _>(right);
+
+	// Larger than a single Noise message.
+	let payload = (0 .. 200_000).map(|i| i as u8).collect::<Vec<u8>>();
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from(payload.clone()))).await.unwrap();
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert_eq!(&message.bytes()[..], &payload[..]);
+}
+
+#[tokio::test]
+async fn mismatched_static_key() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let (initiator, responder, expected) = (noise::generate().unwrap(), noise::generate().unwrap(), noise::generate().unwrap());
+
+	let (left, _right) = future::join(
+		noise::initiate(left, &initiator.private),
+		noise::respond(right, &responder.private)).await;
+
+	// The handshake succeeds, it's up to the application to reject the key.
+	let left = left.unwrap();
+	assert_ne!(left.remote_static(), Some(&expected.public[..]));
+	assert_eq!(left.remote_static(), Some(&responder.public[..]));
+}
+
+#[tokio::test]
+async fn tampered_message() {
+	let (mut left, right) = UnixStream::pair().unwrap();
+	let (initiator, responder) = (noise::generate().unwrap(), noise::generate().unwrap());
+
+	let (_, right) = future::join(
+		noise::initiate(&mut left, &initiator.private),
+		noise::respond(right, &responder.private)).await;
+
+	let mut right = right.unwrap();
+
+	// A full message which was not encrypted with the agreed keys.
+	left.write_all(&[0, 20]).await.unwrap();
+	left.write_all(&[0; 20]).await.unwrap();
+
+	let error = right.read(&mut [0; 64]).await.unwrap_err();
+	assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn truncated_message() {
+	let (mut left, right) = UnixStream::pair().unwrap();
+	let (initiator, responder) = (noise::generate().unwrap(), noise::generate().unwrap());
+
+	let (_, right) = future::join(
+		noise::initiate(&mut left, &initiator.private),
+		noise::respond(right, &responder.private)).await;
+
+	let mut right = right.unwrap();
+
+	// The length announces more than is ever sent.
+	left.write_all(&[0, 100]).await.unwrap();
+	left.write_all(&[0; 10]).await.unwrap();
+	drop(left);
+
+	let error = right.read(&mut [0; 64]).await.unwrap_err();
+	assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,63 @@
+#![cfg(unix)]
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Serialize, de::DeserializeOwned};
+use futures::sink::SinkExt;
+use tokio::{codec::Framed, io::AsyncReadExt, net::UnixStream};
+use protociolla::{Format, Reframed, Codec, Packets, Packet, packet::Cookie};
+
+struct Json;
+
+impl Format for Json {
+	type SerializeError = serde_json::Error;
+	type DeserializeError = serde_json::Error;
+
+	fn serialize<T: Serialize>(value: &T, buffer: &mut BytesMut) -> Result<(), Self::SerializeError> {
+		serde_json::to_writer(&mut buffer.writer(), value)
+	}
+
+	fn deserialize<T: DeserializeOwned>(buffer: &Bytes) -> Result<T, Self::DeserializeError> {
+		serde_json::from_slice(buffer)
+	}
+}
+
+/// Send both packets and read back what went on the wire for each.
+async fn wire(first: Packet<Json>, second: Packet<Json>) -> (Vec<u8>, Vec<u8>) {
+	let (left, mut right) = UnixStream::pair().unwrap();
+	let mut packets = Reframed::<Packets<Json>>::new(Framed::new(left, Codec::default()));
+
+	let sizes = (4 + first.bytes().len(), 4 + second.bytes().len());
+	packets.send(first).await.unwrap();
+	packets.send(second).await.unwrap();
+
+	let mut first = vec![0; sizes.0];
+	right.read_exact(&mut first).await.unwrap();
+
+	let mut second = vec![0; sizes.1];
+	right.read_exact(&mut second).await.unwrap();
+
+	(first, second)
+}
+
+#[tokio::test]
+async fn framed_and_plain_are_identical() {
+	let framed = Packet::<Json>::stream(42, &"hello").unwrap();
+	let plain = Packet::<Json>::new(Cookie::Stream(42), framed.bytes().clone());
+
+	let (framed, plain) = wire(framed, plain).await;
+
+	assert_eq!(framed, plain);
+	assert_eq!(&framed[..4], &[0x80, 42, 0, 7]);
+	assert_eq!(&framed[4..], &b"\"hello\""[..]);
+}
+
+#[tokio::test]
+async fn framed_oneshot_and_single() {
+	let oneshot = Packet::<Json>::oneshot(&1).unwrap();
+	let single = Packet::<Json>::single(7, &2).unwrap();
+
+	let (oneshot, single) = wire(oneshot, single).await;
+
+	assert_eq!(oneshot, &[0, 0, 0, 1, b'1']);
+	assert_eq!(single, &[0, 7, 0, 1, b'2']);
+}
@@ -0,0 +1,75 @@
+use std::{num::NonZeroUsize, time::Duration};
+use bytes::{Bytes, BytesMut};
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::codec::{Decoder, Encoder};
+use protociolla::{Codec, Packet, pipe, packet::{Cookie, Header}};
+
+fn payload(length: usize) -> Bytes {
+	(0 .. length).map(|i| (i % 251) as u8).collect::<Vec<u8>>().into()
+}
+
+async fn round_trip(options: pipe::Options, lengths: &[usize]) {
+	let (mut left, mut right) = protociolla::pair_with::<()>(options);
+
+	for &length in lengths {
+		let payload = payload(length);
+		left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+
+		let mut session = right.next().await.unwrap().unwrap();
+		assert_eq!(session.next().await.unwrap().bytes(), &payload);
+	}
+}
+
+#[tokio::test]
+async fn single_byte_reads() {
+	round_trip(pipe::Options { chunk: NonZeroUsize::new(1), .. Default::default() }, &[0, 1, 3, 4, 5, 1000, 0xfffe, 0xffff, 70_000]).await;
+}
+
+#[tokio::test]
+async fn headers_split_across_reads() {
+	for chunk in 2 .. 8 {
+		round_trip(pipe::Options { chunk: NonZeroUsize::new(chunk), .. Default::default() }, &[0, 1, 2, 3, 5, 7, 11]).await;
+	}
+}
+
+#[tokio::test]
+async fn latency_and_bandwidth() {
+	let options = pipe::Options {
+		latency: Some(Duration::from_millis(5)),
+		bandwidth: NonZeroUsize::new(1024 * 1024),
+		chunk: NonZeroUsize::new(100),
+	};
+
+	round_trip(options, &[10, 10_000, 100_000]).await;
+}
+
+#[test]
+fn decode_byte_by_byte() {
+	let mut encoded = BytesMut::new();
+	let mut codec = Codec::default();
+
+	codec.encode((Header::stream(3, Some(5)), Bytes::from_static(b"hello")), &mut encoded).unwrap();
+	codec.encode((Header::single(3, Some(0)), Bytes::new()), &mut encoded).unwrap();
+
+	let mut buffer = BytesMut::new();
+	let mut fragments = Vec::new();
+
+	for byte in encoded.iter() {
+		buffer.extend_from_slice(&[*byte]);
+
+		while let Some(fragment) = codec.decode(&mut buffer).unwrap() {
+			fragments.push(fragment);
+		}
+	}
+
+	assert!(buffer.is_empty());
+	assert_eq!(fragments.len(), 2);
+
+	assert_eq!(fragments[0].0.cookie(), Some(3));
+	assert!(fragments[0].0.has_more_packets());
+	assert_eq!(&fragments[0].1[..], b"hello");
+
+	assert_eq!(fragments[1].0.cookie(), Some(3));
+	assert!(!fragments[1].0.has_more_packets());
+	assert!(fragments[1].1.is_empty());
+}
@@ -0,0 +1,222 @@
+use std::{io, sync::{Arc, Mutex}, time::{Duration, Instant}, collections::HashSet};
+use bytes::Bytes;
+use futures::{future::{self, Either}, channel::mpsc::{unbounded, UnboundedReceiver}, stream::StreamExt, sink::SinkExt};
+use tokio::{timer, codec::FramedRead};
+use protociolla::{Codec, Message, Mode, Reframed, Sessions, Session, pool::Pool, pipe::{self, Pipe}};
+
+/// The far end of a pooled connection.
+type Peer = FramedRead<Pipe, Codec>;
+
+/// Connects the pool over pipes, handing the far ends to the test.
+struct Network {
+	down: Arc<Mutex<HashSet<usize>>>,
+	peers: UnboundedReceiver<(usize, Peer)>,
+}
+
+impl Network {
+	fn new() -> (Self, impl FnMut(usize) -> future::Ready<Result<Reframed<Sessions>, io::Error>> + Send + 'static) {
+		let down = Arc::new(Mutex::new(HashSet::new()));
+		let (sender, peers) = unbounded();
+
+		let refused = down.clone();
+		let connect = move |index| {
+			if refused.lock().unwrap().contains(&index) {
+				return future::ready(Err(io::ErrorKind::ConnectionRefused.into()));
+			}
+
+			let (ours, theirs) = pipe::duplex(pipe::Options::default());
+			sender.unbounded_send((index, FramedRead::new(theirs, Codec))).unwrap();
+
+			future::ready(Ok(protociolla::mi(ours)))
+		};
+
+		(Network { down, peers }, connect)
+	}
+
+	/// Refuse or accept connections at the index.
+	fn set_down(&self, index: usize, down: bool) {
+		if down {
+			self.down.lock().unwrap().insert(index);
+		}
+		else {
+			self.down.lock().unwrap().remove(&index);
+		}
+	}
+
+	/// Wait for the next connection.
+	async fn accept(&mut self) -> (usize, Peer) {
+		self.peers.next().await.unwrap()
+	}
+}
+
+async fn sleep(millis: u64) {
+	timer::delay(Instant::now() + Duration::from_millis(millis)).await;
+}
+
+/// Open a session and send a payload over it.
+async fn open(pool: &Pool<(), impl FnMut(usize) -> future::Ready<Result<Reframed<Sessions>, io::Error>> + Send + 'static>, payload: u8) -> Session {
+	let mut session = pool.open().unwrap();
+	session.send(Message::new(Mode::End, Bytes::from(vec![payload]))).await.unwrap();
+
+	session
+}
+
+/// The payloads that reach the peer shortly.
+async fn received(peer: &mut Peer) -> Vec<u8> {
+	let mut payloads = Vec::new();
+
+	loop {
+		match future::select(peer.next(), timer::delay(Instant::now() + Duration::from_millis(100))).await {
+			Either::Left((Some(fragment), _)) =>
+				payloads.extend_from_slice(&fragment.unwrap().1),
+
+			_ =>
+				return payloads,
+		}
+	}
+}
+
+#[tokio::test]
+async fn least_sessions() {
+	let (mut network, connect) = Network::new();
+	let pool = Pool::new(3, connect).await.unwrap();
+
+	let mut peers = Vec::new();
+	for _ in 0 .. 3 {
+		peers.push(network.accept().await.1);
+	}
+
+	let mut sessions = Vec::new();
+	for payload in 0 .. 6 {
+		sessions.push(open(&pool, payload).await);
+	}
+
+	assert_eq!(pool.sessions(), 6);
+	assert_eq!(received(&mut peers[0]).await, vec![0, 3]);
+	assert_eq!(received(&mut peers[1]).await, vec![1, 4]);
+	assert_eq!(received(&mut peers[2]).await, vec![2, 5]);
+
+	// Room is made on the middle connection only.
+	sessions.remove(4);
+	sessions.remove(1);
+
+	for payload in 6 .. 8 {
+		sessions.push(open(&pool, payload).await);
+	}
+
+	assert_eq!(received(&mut peers[0]).await, vec![]);
+	assert_eq!(received(&mut peers[1]).await, vec![6, 7]);
+	assert_eq!(received(&mut peers[2]).await, vec![]);
+}
+
+#[tokio::test]
+async fn connects_in_background() {
+	let (mut network, connect) = Network::new();
+	network.set_down(1, true);
+
+	let pool = Pool::new(2, connect).await.unwrap();
+	let (index, _first) = network.accept().await;
+	assert_eq!(index, 0);
+
+	network.set_down(1, false);
+
+	let (index, _second) = network.accept().await;
+	assert_eq!(index, 1);
+	drop(pool);
+}
+
+#[tokio::test]
+async fn nothing_to_connect_to() {
+	let (network, connect) = Network::new();
+	network.set_down(0, true);
+	network.set_down(1, true);
+
+	let error = Pool::new(2, connect).await.err().unwrap();
+	assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn every_connection_down() {
+	let (mut network, connect) = Network::new();
+	let pool = Pool::new(2, connect).await.unwrap();
+	let peers = vec![network.accept().await.1, network.accept().await.1];
+
+	network.set_down(0, true);
+	network.set_down(1, true);
+	drop(peers);
+
+	let error = loop {
+		match pool.open() {
+			Ok(_) =>
+				sleep(10).await,
+
+			Err(error) =>
+				break error,
+		}
+	};
+
+	assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+
+	// Back up as soon as the peers are.
+	network.set_down(1, false);
+	let (index, mut peer) = network.accept().await;
+	assert_eq!(index, 1);
+
+	let mut session = loop {
+		match pool.open() {
+			Ok(session) =>
+				break session,
+
+			Err(_) =>
+				sleep(10).await,
+		}
+	};
+
+	session.send(Message::new(Mode::End, Bytes::from_static(b"back"))).await.unwrap();
+	assert_eq!(received(&mut peer).await, b"back");
+}
+
+#[tokio::test]
+async fn broken_connection_is_replaced() {
+	let (mut network, connect) = Network::new();
+	let pool = Pool::new(2, connect).await.unwrap();
+	let (_, dead) = network.accept().await;
+	let (_, mut survivor) = network.accept().await;
+
+	let mut sessions = Vec::new();
+	for payload in 0 .. 4 {
+		sessions.push(open(&pool, payload).await);
+	}
+
+	assert_eq!(received(&mut survivor).await, vec![1, 3]);
+
+	// The sessions on the broken connection go away with it.
+	network.set_down(0, true);
+	drop(dead);
+
+	while pool.sessions() != 2 {
+		sleep(10).await;
+	}
+
+	// The connection is forgotten right after its sessions.
+	sleep(50).await;
+
+	// New sessions go to the survivor meanwhile.
+	for payload in 4 .. 6 {
+		sessions.push(open(&pool, payload).await);
+	}
+
+	assert_eq!(received(&mut survivor).await, vec![4, 5]);
+
+	// Until the broken one is replaced, and being the least loaded gets them.
+	network.set_down(0, false);
+	let (index, mut replacement) = network.accept().await;
+	assert_eq!(index, 0);
+
+	// The pool picks it up right after connecting.
+	sleep(50).await;
+	sessions.push(open(&pool, 6).await);
+
+	assert_eq!(received(&mut replacement).await, vec![6]);
+	assert_eq!(received(&mut survivor).await, vec![]);
+}
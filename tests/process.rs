@@ -0,0 +1,50 @@
+#![cfg(unix)]
+
+use std::{env, io::{Read, Seek, SeekFrom}, process::Stdio};
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::process::Command;
+use protociolla::{Packet, packet::Cookie, process};
+
+/// The `plugin` example, built along the tests.
+fn plugin() -> Command {
+	let mut path = env::current_exe().unwrap();
+	path.pop();
+
+	if path.ends_with("deps") {
+		path.pop();
+	}
+
+	Command::new(path.join("examples").join("plugin"))
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let mut child = process::spawn::<()>(&mut plugin()).unwrap();
+
+	for payload in &[&b"hello"[..], &[0; 0x1_0000][..]] {
+		child.send(Packet::new(Cookie::Oneshot, Bytes::from(*payload))).await.unwrap();
+
+		let mut session = child.next().await.unwrap().unwrap();
+		assert_eq!(&session.next().await.unwrap().bytes()[..], *payload);
+	}
+}
+
+#[tokio::test]
+async fn reaped_on_exit() {
+	let mut stderr = tempfile::tempfile().unwrap();
+	let mut child = process::spawn::<()>(plugin().stderr(Stdio::from(stderr.try_clone().unwrap()))).unwrap();
+
+	child.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"exit"))).await.unwrap();
+
+	// The exit status is only known once the child has been waited on.
+	let error = child.next().await.unwrap().unwrap_err();
+	assert!(error.to_string().contains("exit code: 0"), "{}", error);
+
+	// Standard error is left to the child.
+	let mut output = String::new();
+	stderr.seek(SeekFrom::Start(0)).unwrap();
+	stderr.read_to_string(&mut output).unwrap();
+
+	assert_eq!(output, "plugin started\n");
+}
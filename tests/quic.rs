@@ -0,0 +1,73 @@
+#![cfg(feature = "quic")]
+
+use bytes::Bytes;
+use futures::{future::{self, TryFutureExt}, stream::StreamExt, sink::SinkExt};
+use protociolla::{Packet, Message, Mode, packet::Cookie, quic};
+
+async fn pair() -> (quic::Connection<()>, quic::Connection<()>) {
+	let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+	let der = certificate.serialize_der().unwrap();
+	let key = certificate.serialize_private_key_der();
+
+	let mut server = quinn::ServerConfigBuilder::default();
+	server.certificate(
+		quinn::CertificateChain::from_certs(vec![quinn::Certificate::from_der(&der).unwrap()]),
+		quinn::PrivateKey::from_der(&key).unwrap()).unwrap();
+
+	let mut builder = quinn::Endpoint::builder();
+	builder.listen(server.build());
+
+	let (driver, endpoint, mut incoming) = builder.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+	let address = endpoint.local_addr().unwrap();
+	tokio::spawn(driver.unwrap_or_else(|_| ()));
+
+	let mut client = quinn::ClientConfigBuilder::default();
+	client.add_certificate_authority(quinn::Certificate::from_der(&der).unwrap()).unwrap();
+
+	let mut builder = quinn::Endpoint::builder();
+	builder.default_client_config(client.build());
+
+	let (driver, endpoint, _) = builder.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+	tokio::spawn(driver.unwrap_or_else(|_| ()));
+
+	let (client, server) = future::join(
+		quic::connect::<()>(&endpoint, &address, "localhost"),
+		async { quic::accept::<()>(incoming.next().await.unwrap()).await }).await;
+
+	(client.unwrap(), server.unwrap())
+}
+
+#[tokio::test]
+async fn session() {
+	let (client, mut server) = pair().await;
+
+	let mut session = client.open().await.unwrap();
+	session.send(Message::new(Mode::More, Bytes::from_static(b"ping"))).await.unwrap();
+
+	let mut incoming = server.next().await.unwrap().unwrap();
+	let message = incoming.next().await.unwrap();
+	assert_eq!(&message.bytes()[..], b"ping");
+
+	incoming.send(Message::new(Mode::End, Bytes::from_static(b"pong"))).await.unwrap();
+
+	let reply = session.next().await.unwrap();
+	assert_eq!(&reply.bytes()[..], b"pong");
+
+	match reply.mode() {
+		Mode::End => (),
+		mode => panic!("expected the session to end, got {:?}", mode),
+	}
+}
+
+#[tokio::test]
+async fn oneshot() {
+	let (client, mut server) = pair().await;
+
+	for &length in &[0, 10, 100_000] {
+		let payload = (0 .. length).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+		client.send(Packet::new(Cookie::Oneshot, Bytes::from(payload.clone()))).await.unwrap();
+
+		let mut session = server.next().await.unwrap().unwrap();
+		assert_eq!(&session.next().await.unwrap().bytes()[..], &payload[..]);
+	}
+}
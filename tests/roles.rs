@@ -0,0 +1,50 @@
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use protociolla::{Message, Mode, Packet, Exhausted, packet::{self, Cookie}};
+
+#[tokio::test]
+async fn exhausted() {
+	let (left, _right) = protociolla::pair::<()>();
+
+	// The initiator owns the odd cookies.
+	let mut sessions = (0 ..= packet::MAX_COOKIE / 2).map(|_| left.open().unwrap()).collect::<Vec<_>>();
+	assert!(Exhausted::find(&left.open().err().unwrap()).is_some());
+
+	sessions.pop();
+	left.open().unwrap();
+}
+
+#[tokio::test]
+async fn trespassing() {
+	let (mut left, mut right) = protociolla::pair::<()>();
+
+	// Cookie 1 is owned by the initiator.
+	right.send(Packet::new(Cookie::Single(1), Bytes::from_static(b"mine"))).await.unwrap();
+	right.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"after"))).await.unwrap();
+
+	let mut session = left.next().await.unwrap().unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"after");
+	assert_eq!(left.handle().dropped(), 1);
+}
+
+#[tokio::test]
+async fn after_the_peer_ended() {
+	let (mut left, mut right) = protociolla::pair::<()>();
+
+	// The first session of the initiator is on cookie 1.
+	let mut session = left.open().unwrap();
+	session.send(Message::new(Mode::More, Bytes::from_static(b"ping"))).await.unwrap();
+
+	let mut incoming = right.next().await.unwrap().unwrap();
+	incoming.next().await.unwrap();
+	incoming.send(Message::new(Mode::End, Bytes::from_static(b"pong"))).await.unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"pong");
+
+	// A late packet doesn't break the connection.
+	right.send(Packet::new(Cookie::Stream(1), Bytes::from_static(b"late"))).await.unwrap();
+	right.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"after"))).await.unwrap();
+
+	let mut next = left.next().await.unwrap().unwrap();
+	assert_eq!(&next.next().await.unwrap().bytes()[..], b"after");
+	assert_eq!(left.handle().dropped(), 1);
+}
@@ -0,0 +1,110 @@
+use bytes::{Bytes, BytesMut};
+use tokio::codec::{Decoder, Encoder};
+use protociolla::{serial::Codec, packet::Header};
+
+/// Encode the fragments, one buffer per COBS frame.
+fn frames(fragments: Vec<(Header, Bytes)>) -> Vec<BytesMut> {
+	let mut codec = Codec::new(None);
+
+	fragments.into_iter().map(|fragment| {
+		let mut buffer = BytesMut::new();
+		codec.encode(fragment, &mut buffer).unwrap();
+		buffer
+	}).collect()
+}
+
+/// Packet 1 is a single fragment, packet 2 spans three and packet 3 is a
+/// single fragment again.
+fn packets() -> Vec<(Header, Bytes)> {
+	vec![
+		(Header::single(1, Some(1)), Bytes::from_static(b"a")),
+
+		(Header::single(2, None), Bytes::from(vec![1; 0xfffe])),
+		(Header::single(2, None), Bytes::from(vec![2; 0xfffe])),
+		(Header::single(2, Some(3)), Bytes::from_static(b"end")),
+
+		(Header::single(3, Some(1)), Bytes::from_static(b"c")),
+	]
+}
+
+/// Decode the frames, leaving out the one at the given index.
+fn decode(skip: Option<usize>) -> (Codec, Vec<(Header, Bytes)>) {
+	let mut codec = Codec::new(None);
+	let mut buffer = BytesMut::new();
+
+	for (index, frame) in frames(packets()).into_iter().enumerate() {
+		if Some(index) != skip {
+			buffer.extend_from_slice(&frame);
+		}
+	}
+
+	let mut fragments = Vec::new();
+	while let Some(fragment) = codec.decode(&mut buffer).unwrap() {
+		fragments.push(fragment);
+	}
+
+	(codec, fragments)
+}
+
+fn cookies(fragments: &[(Header, Bytes)]) -> Vec<Option<u16>> {
+	fragments.iter().map(|(header, _)| header.cookie()).collect()
+}
+
+#[test]
+fn intact() {
+	let (codec, fragments) = decode(None);
+
+	assert_eq!(cookies(&fragments), vec![Some(1), Some(2), Some(2), Some(2), Some(3)]);
+	assert_eq!(codec.discarded(), 0);
+}
+
+#[test]
+fn missing_first_fragment() {
+	let (codec, fragments) = decode(Some(1));
+
+	assert_eq!(cookies(&fragments), vec![Some(1), Some(3)]);
+	assert_eq!(codec.discarded(), 1);
+}
+
+#[test]
+fn missing_middle_fragment() {
+	let (codec, fragments) = decode(Some(2));
+
+	assert_eq!(cookies(&fragments), vec![Some(1), Some(3)]);
+	assert_eq!(codec.discarded(), 1);
+}
+
+#[test]
+fn missing_last_fragment() {
+	let (codec, fragments) = decode(Some(3));
+
+	assert_eq!(cookies(&fragments), vec![Some(1), Some(3)]);
+	assert_eq!(&fragments[1].1[..], b"c");
+	assert_eq!(codec.discarded(), 1);
+}
+
+#[test]
+fn garbled_frame() {
+	let mut codec = Codec::new(None);
+	let mut buffer = BytesMut::new();
+
+	for (index, mut frame) in frames(packets()).into_iter().enumerate() {
+		// Turn a byte in the middle of the second fragment of packet 2 into a
+		// delimiter, splitting it into two frames that fail to decode.
+		if index == 2 {
+			let middle = frame.len() / 2;
+			frame[middle] = 0;
+		}
+
+		buffer.extend_from_slice(&frame);
+	}
+
+	let mut fragments = Vec::new();
+	while let Some(fragment) = codec.decode(&mut buffer).unwrap() {
+		fragments.push(fragment);
+	}
+
+	assert_eq!(cookies(&fragments), vec![Some(1), Some(3)]);
+	assert_eq!(codec.discarded(), 1);
+	assert_eq!(codec.dropped(), 2);
+}
@@ -0,0 +1,131 @@
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use protociolla::{Message, Mode, Packet, Session, Opener, Exhausted, packet::{self, Cookie}};
+
+#[tokio::test]
+async fn dropped_sessions_are_forgotten() {
+	let (left, _right) = protociolla::pair::<()>();
+	let opener = left.handle();
+
+	for _ in 0 .. 100_000 {
+		let session = opener.open().unwrap();
+		assert_eq!(opener.sessions(), 1);
+
+		drop(session);
+		assert_eq!(opener.sessions(), 0);
+	}
+}
+
+#[tokio::test]
+async fn ended_sessions_are_forgotten() {
+	let (left, mut right) = protociolla::pair::<()>();
+	let opener = left.handle();
+
+	for _ in 0 .. 100 {
+		let mut session = opener.open().unwrap();
+		session.send(Message::new(Mode::More, Bytes::from_static(b"ping"))).await.unwrap();
+		session.send(Message::new(Mode::End, Bytes::from_static(b"ping"))).await.unwrap();
+
+		let mut incoming = right.next().await.unwrap().unwrap();
+		incoming.next().await.unwrap();
+		incoming.next().await.unwrap();
+		incoming.send(Message::new(Mode::End, Bytes::from_static(b"pong"))).await.unwrap();
+
+		// Both ends are done while the session is still around.
+		session.next().await.unwrap();
+		assert_eq!(opener.sessions(), 0);
+	}
+
+	assert_eq!(right.handle().sessions(), 0);
+}
+
+#[tokio::test]
+async fn dropped_while_peer_is_sending() {
+	let (mut left, mut right) = protociolla::pair::<()>();
+
+	let mut session = left.open().unwrap();
+	session.send(Message::new(Mode::More, Bytes::from_static(b"ping"))).await.unwrap();
+
+	let mut incoming = right.next().await.unwrap().unwrap();
+	incoming.next().await.unwrap();
+
+	drop(session);
+	assert_eq!(left.handle().sessions(), 0);
+
+	// The peer going on doesn't start a new session.
+	incoming.send(Message::new(Mode::More, Bytes::from_static(b"late"))).await.unwrap();
+	incoming.send(Message::new(Mode::End, Bytes::from_static(b"late"))).await.unwrap();
+
+	// Give the late messages time to go out before the marker.
+	tokio::timer::delay(Instant::now() + Duration::from_millis(100)).await;
+
+	right.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"after"))).await.unwrap();
+
+	let mut next = left.next().await.unwrap().unwrap();
+	assert_eq!(&next.next().await.unwrap().bytes()[..], b"after");
+}
+
+#[tokio::test]
+async fn dropped_session_is_ended() {
+	let (left, mut right) = protociolla::pair::<()>();
+
+	let mut session = left.open().unwrap();
+	session.send(Message::new(Mode::More, Bytes::from_static(b"ping"))).await.unwrap();
+	drop(session);
+
+	let mut incoming = right.next().await.unwrap().unwrap();
+	assert_eq!(&incoming.next().await.unwrap().bytes()[..], b"ping");
+
+	// The peer is told the session is gone.
+	let end = incoming.next().await.unwrap();
+	assert!(end.bytes().is_empty());
+	assert!(match end.mode() { Mode::End => true, _ => false });
+}
+
+/// Open a session, waiting for a cookie to be released if needed.
+async fn open_before(opener: &Opener, deadline: Instant) -> Session {
+	loop {
+		match opener.open() {
+			Ok(session) =>
+				return session,
+
+			Err(error) => {
+				assert!(Exhausted::find(&error).is_some());
+				assert!(Instant::now() < deadline, "cookies were never released");
+
+				tokio::timer::delay(Instant::now() + Duration::from_millis(10)).await;
+			}
+		}
+	}
+}
+
+#[tokio::test]
+async fn dropped_more_than_cookies() {
+	let (left, mut right) = protociolla::pair::<()>();
+	let opener = left.handle();
+	let deadline = Instant::now() + Duration::from_secs(30);
+
+	// The peer drops every session once it's told it's gone.
+	tokio::spawn(async move {
+		while let Some(session) = right.next().await {
+			let mut session = session.unwrap();
+			session.next().await.unwrap();
+			session.next().await.unwrap();
+		}
+	});
+
+	// The initiator owns the odd cookies, so this goes around them twice.
+	for _ in 0 .. packet::MAX_COOKIE + 1 {
+		let mut session = open_before(opener, deadline).await;
+		session.send(Message::new(Mode::More, Bytes::from_static(b"ping"))).await.unwrap();
+	}
+
+	// Every cookie is released once the peer ended its side.
+	let mut sessions = Vec::new();
+	for _ in 0 ..= packet::MAX_COOKIE / 2 {
+		sessions.push(open_before(opener, deadline).await);
+	}
+
+	assert!(Exhausted::find(&opener.open().err().unwrap()).is_some());
+}
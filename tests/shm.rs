@@ -0,0 +1,52 @@
+#![cfg(target_os = "linux")]
+
+use std::{io, os::unix::io::FromRawFd};
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use protociolla::{Packet, packet::Cookie, shm, unix::Fd};
+
+fn payload(length: usize) -> Bytes {
+	(0 .. length).map(|i| (i % 251) as u8).collect::<Vec<u8>>().into()
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let (left, fds) = shm::Shared::create(64 * 1024).unwrap();
+	let right = shm::Shared::open(fds).unwrap();
+	let (mut left, mut right) = (shm::mi::<()>(left), shm::mi::<()>(right));
+
+	// The last ones are bigger than a fragment and than the ring itself.
+	for &length in &[0, 1, 1000, 0xfffe, 0xffff, 200_000] {
+		let payload = payload(length);
+
+		let sending = left.send(Packet::new(Cookie::Oneshot, payload.clone()));
+		let receiving = async {
+			let mut session = right.next().await.unwrap().unwrap();
+			session.next().await.unwrap()
+		};
+
+		let (sent, received) = futures::future::join(sending, receiving).await;
+		sent.unwrap();
+		assert_eq!(received.bytes(), &payload);
+	}
+}
+
+#[test]
+fn unsealed() {
+	let (_, mut fds) = shm::Shared::create(4096).unwrap();
+
+	let fd = unsafe { libc::memfd_create(b"unsealed\0".as_ptr() as *const _, libc::MFD_CLOEXEC) };
+	assert!(fd >= 0);
+	assert_eq!(unsafe { libc::ftruncate(fd, 64 * 1024) }, 0);
+
+	// Swap the ring we would write to for one the peer could shrink.
+	fds[3] = unsafe { Fd::from_raw_fd(fd) };
+
+	match shm::Shared::open(fds) {
+		Err(error) =>
+			assert_eq!(error.kind(), io::ErrorKind::InvalidData),
+
+		Ok(_) =>
+			panic!("expected the unsealed ring to be refused"),
+	}
+}
@@ -0,0 +1,94 @@
+#![cfg(unix)]
+
+use std::io;
+use bytes::Bytes;
+use futures::{stream::{self, StreamExt}, sink::SinkExt};
+use tokio::net::UnixStream;
+use protociolla::{Body, Packet, Message, packet::Cookie};
+
+fn payload(length: usize) -> Bytes {
+	(0 .. length).map(|i| i as u8).collect::<Vec<u8>>().into()
+}
+
+async fn receive(message: &Message) -> Vec<Bytes> {
+	let mut body = message.body().expect("no body").clone();
+	let mut chunks = Vec::new();
+
+	while let Some(chunk) = body.next().await {
+		chunks.push(chunk.unwrap());
+	}
+
+	chunks
+}
+
+#[tokio::test]
+async fn chunked_receive() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi::<(), _>(left);
+	let mut right = protociolla::mi_streaming::<(), _>(right);
+
+	let payload = payload(0xfffe * 3 + 10);
+	left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"small"))).await.unwrap();
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	let chunks = receive(&message).await;
+
+	assert_eq!(chunks.len(), 4);
+	assert!(chunks.iter().all(|chunk| chunk.len() <= 0xfffe));
+	assert_eq!(chunks.concat(), &payload[..]);
+
+	// Payloads in a single fragment are not streamed.
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert!(message.body().is_none());
+	assert_eq!(&message.bytes()[..], b"small");
+}
+
+#[tokio::test]
+async fn streamed_send() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi_streaming::<(), _>(left);
+	let mut right = protociolla::mi::<(), _>(right);
+
+	let payload = payload(0xfffe * 2 + 1);
+	let chunks = payload.chunks(1000).map(|chunk| Ok(Bytes::from(chunk))).collect::<Vec<_>>();
+	left.send(Packet::streamed(Cookie::Oneshot, Body::new(stream::iter(chunks)))).await.unwrap();
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert_eq!(message.bytes(), &payload);
+}
+
+#[tokio::test]
+async fn dropped_body() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi::<(), _>(left);
+	let mut right = protociolla::mi_streaming::<(), _>(right);
+
+	// Enough fragments to fill the body channel if they were kept.
+	left.send(Packet::new(Cookie::Oneshot, payload(0xfffe * 40))).await.unwrap();
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"after"))).await.unwrap();
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert!(message.body().is_some());
+	drop(message);
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert_eq!(&message.bytes()[..], b"after");
+}
+
+#[tokio::test]
+async fn body_error_aborts() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi_streaming::<(), _>(left);
+	let mut right = protociolla::mi::<(), _>(right);
+
+	let chunks = vec![
+		Ok(payload(0xfffe + 5)),
+		Err(io::Error::new(io::ErrorKind::Other, "broken")),
+	];
+
+	left.send(Packet::streamed(Cookie::Oneshot, Body::new(stream::iter(chunks)))).await.unwrap();
+
+	// The truncated packet is never delivered, the connection ends instead.
+	assert!(right.next().await.is_none());
+}
@@ -0,0 +1,65 @@
+#![cfg(all(unix, feature = "tls"))]
+
+use std::io;
+use bytes::Bytes;
+use futures::{future, stream::StreamExt, sink::SinkExt};
+use tokio_rustls::rustls::{ClientConfig, ServerConfig, NoClientAuth, Certificate, PrivateKey};
+use tokio::net::UnixStream;
+use protociolla::{Packet, Reframed, Sessions, packet::Cookie, tls};
+
+fn configs() -> (ClientConfig, ServerConfig) {
+	let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+	let der = Certificate(certificate.serialize_der().unwrap());
+
+	let mut client = ClientConfig::new();
+	client.root_store.add(&der).unwrap();
+
+	let mut server = ServerConfig::new(NoClientAuth::new());
+	server.set_single_cert(vec![der], PrivateKey(certificate.serialize_private_key_der())).unwrap();
+
+	(client, server)
+}
+
+type Connection = Result<Reframed<Sessions<()>>, io::Error>;
+
+async fn handshake(domain: &str, client: ClientConfig, server: ServerConfig, alpn: bool) -> (Connection, Connection) {
+	let (left, right) = UnixStream::pair().unwrap();
+	let client = if alpn { tls::client(client) } else { std::sync::Arc::new(client) };
+
+	future::join(
+		tls::connect::<(), _>(left, domain, client),
+		tls::accept::<(), _>(right, tls::server(server))).await
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let (client, server) = configs();
+	let (client, server) = handshake("localhost", client, server, true).await;
+	let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+	client.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"hello"))).await.unwrap();
+	let mut session = server.next().await.unwrap().unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"hello");
+
+	server.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"world"))).await.unwrap();
+	let mut session = client.next().await.unwrap().unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"world");
+}
+
+#[tokio::test]
+async fn certificate_mismatch() {
+	let (client, server) = configs();
+	let (client, server) = handshake("example.com", client, server, true).await;
+
+	assert!(client.is_err());
+	assert!(server.is_err());
+}
+
+#[tokio::test]
+async fn missing_alpn() {
+	let (client, server) = configs();
+	let (client, server) = handshake("localhost", client, server, false).await;
+
+	assert_eq!(client.err().unwrap().kind(), io::ErrorKind::InvalidData);
+	assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
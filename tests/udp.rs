@@ -0,0 +1,94 @@
+use bytes::Bytes;
+use futures::{future, channel::oneshot, stream::StreamExt, sink::SinkExt};
+use tokio::net::UdpSocket;
+use protociolla::{Packet, Reframed, Sessions, packet::Cookie, udp};
+
+async fn pair() -> (Reframed<Sessions<()>>, Reframed<Sessions<()>>) {
+	let left = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let right = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let (left_addr, right_addr) = (left.local_addr().unwrap(), right.local_addr().unwrap());
+
+	let (left, right) = future::join(udp::mi(left, right_addr), udp::mi(right, left_addr)).await;
+	(left.unwrap(), right.unwrap())
+}
+
+fn payload(seed: usize, length: usize) -> Bytes {
+	(0 .. length).map(|i| ((i + seed) % 251) as u8).collect::<Vec<u8>>().into()
+}
+
+/// Send the payloads while receiving as many from the other end.
+async fn exchange(connection: Reframed<Sessions<()>>, seed: usize, theirs: usize, count: usize) {
+	let (mut sink, mut stream) = connection.split();
+
+	let sending = async move {
+		for i in 0 .. count {
+			sink.send(Packet::new(Cookie::Oneshot, payload(seed + i, 100_000))).await.unwrap();
+		}
+
+		sink
+	};
+
+	let receiving = async move {
+		for i in 0 .. count {
+			let mut session = stream.next().await.unwrap().unwrap();
+			assert_eq!(session.next().await.unwrap().bytes(), &payload(theirs + i, 100_000));
+		}
+	};
+
+	future::join(sending, receiving).await;
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let (mut left, mut right) = pair().await;
+
+	for &length in &[0, 10, 1200, 1201, 70_000] {
+		left.send(Packet::new(Cookie::Oneshot, payload(0, length))).await.unwrap();
+
+		let mut session = right.next().await.unwrap().unwrap();
+		assert_eq!(session.next().await.unwrap().bytes(), &payload(0, length));
+	}
+}
+
+#[tokio::test]
+async fn bulk_both_directions() {
+	let (left, right) = pair().await;
+
+	// Each direction sends way more than the window at once.
+	future::join(exchange(left, 0, 1000, 20), exchange(right, 1000, 0, 20)).await;
+}
+
+#[tokio::test]
+async fn slow_consumer() {
+	let (left, right) = pair().await;
+	let (mut left_sink, mut left_stream) = left.split();
+	let (mut right_sink, mut right_stream) = right.split();
+	let (done, received) = oneshot::channel();
+
+	for i in 0 .. 10 {
+		left_sink.send(Packet::new(Cookie::Oneshot, payload(i, 100_000))).await.unwrap();
+		right_sink.send(Packet::new(Cookie::Oneshot, payload(1000 + i, 100_000))).await.unwrap();
+	}
+
+	// The right end doesn't read anything until everything it sent arrived,
+	// which needs the acknowledgements it receives to still go through.
+	let left = async move {
+		for i in 0 .. 10 {
+			let mut session = left_stream.next().await.unwrap().unwrap();
+			assert_eq!(session.next().await.unwrap().bytes(), &payload(1000 + i, 100_000));
+		}
+
+		done.send(()).unwrap();
+	};
+
+	let right = async move {
+		received.await.unwrap();
+
+		for i in 0 .. 10 {
+			let mut session = right_stream.next().await.unwrap().unwrap();
+			assert_eq!(session.next().await.unwrap().bytes(), &payload(i, 100_000));
+		}
+	};
+
+	future::join(left, right).await;
+}
@@ -0,0 +1,135 @@
+#![cfg(unix)]
+
+use std::{io, fs::File, io::{Read, Write}, os::unix::io::{FromRawFd, IntoRawFd}};
+use bytes::Bytes;
+use futures::{future, stream::{self, StreamExt}, sink::SinkExt};
+use tokio::net::UnixStream;
+use protociolla::{Body, Packet, packet::Cookie, unix::{self, Credentials, Fd}};
+
+fn ours() -> Credentials {
+	Credentials {
+		pid: if cfg!(any(target_os = "linux", target_os = "android")) {
+			Some(unsafe { libc::getpid() })
+		}
+		else {
+			None
+		},
+
+		uid: unsafe { libc::getuid() },
+		gid: unsafe { libc::getgid() },
+	}
+}
+
+fn pipe() -> (Fd, Fd) {
+	let mut fds = [0; 2];
+	assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+	unsafe { (Fd::from_raw_fd(fds[0]), Fd::from_raw_fd(fds[1])) }
+}
+
+fn payload(length: usize) -> Bytes {
+	(0 .. length).map(|i| i as u8).collect::<Vec<u8>>().into()
+}
+
+#[test]
+fn socket_credentials() {
+	let (left, right) = std::os::unix::net::UnixStream::pair().unwrap();
+
+	assert_eq!(unix::credentials(&left).unwrap(), ours());
+	assert_eq!(unix::credentials(&right).unwrap(), ours());
+}
+
+#[tokio::test]
+async fn connect_and_accept() {
+	let directory = tempfile::tempdir().unwrap();
+	let path = directory.path().join("socket");
+	let mut listener = unix::bind::<()>(&path).unwrap();
+
+	let (server, client) = future::join(listener.accept(), unix::connect::<()>(&path)).await;
+	let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+	client.send(Packet::new(Cookie::Single(1), Bytes::from_static(b"ping"))).await.unwrap();
+	server.send(Packet::new(Cookie::Single(2), Bytes::from_static(b"pong"))).await.unwrap();
+
+	let mut session = server.next().await.unwrap().unwrap();
+	assert_eq!(session.peer().credentials(), Some(&ours()));
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"ping");
+
+	let mut session = client.next().await.unwrap().unwrap();
+	assert_eq!(session.peer().credentials(), Some(&ours()));
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"pong");
+}
+
+#[tokio::test]
+async fn socket_pair() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = unix::mi::<()>(left).unwrap();
+	let mut right = unix::mi::<()>(right).unwrap();
+
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"hello"))).await.unwrap();
+
+	let session = right.next().await.unwrap().unwrap();
+	assert_eq!(session.peer().credentials(), Some(&ours()));
+}
+
+#[tokio::test]
+async fn pipe_across() {
+	let (left, right) = std::os::unix::net::UnixStream::pair().unwrap();
+	let mut left = unix::mi_with_fds::<()>(left).unwrap();
+	let mut right = unix::mi_with_fds::<()>(right).unwrap();
+
+	let (reader, writer) = pipe();
+	let mut writer = unsafe { File::from_raw_fd(writer.into_raw_fd()) };
+	writer.write_all(b"through the pipe").unwrap();
+	drop(writer);
+
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"fd")).attach(vec![reader])).await.unwrap();
+
+	let message = right.next().await.unwrap().unwrap().next().await.unwrap();
+	assert_eq!(&message.bytes()[..], b"fd");
+
+	let mut fds = message.take_fds();
+	assert_eq!(fds.len(), 1);
+	assert!(message.take_fds().is_empty());
+
+	let mut reader = unsafe { File::from_raw_fd(fds.remove(0).into_raw_fd()) };
+	let mut content = String::new();
+	reader.read_to_string(&mut content).unwrap();
+
+	assert_eq!(content, "through the pipe");
+}
+
+#[tokio::test]
+async fn fds_on_fragments() {
+	let (left, right) = std::os::unix::net::UnixStream::pair().unwrap();
+	let mut left = unix::mi_with_fds::<()>(left).unwrap();
+	let mut right = unix::mi_with_fds::<()>(right).unwrap();
+
+	let payload = payload(0xfffe * 3 + 10);
+	let (reader, writer) = pipe();
+
+	left.send(Packet::new(Cookie::Oneshot, payload.clone()).attach(vec![reader, writer])).await.unwrap();
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"none"))).await.unwrap();
+
+	let mut session = right.next().await.unwrap().unwrap();
+	let message = session.next().await.unwrap();
+	assert_eq!(message.bytes(), &payload);
+	assert_eq!(message.take_fds().len(), 2);
+
+	let mut session = right.next().await.unwrap().unwrap();
+	let message = session.next().await.unwrap();
+	assert_eq!(&message.bytes()[..], b"none");
+	assert!(message.take_fds().is_empty());
+}
+
+#[tokio::test]
+async fn streamed_rejects_fds() {
+	let (left, _right) = std::os::unix::net::UnixStream::pair().unwrap();
+	let mut left = unix::packets_with_fds::<()>(left).unwrap().sink;
+
+	let (reader, _writer) = pipe();
+	let body = Body::new(stream::iter(vec![Ok(Bytes::from_static(b"streamed"))]));
+	let error = left.send(Packet::streamed(Cookie::Oneshot, body).attach(vec![reader])).await.unwrap_err();
+
+	assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,128 @@
+#![cfg(unix)]
+
+use std::{io::{self, IoSlice}, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}};
+use bytes::{Bytes, BytesMut};
+use futures::{stream::{self, StreamExt}, sink::SinkExt};
+use tokio::{codec::Encoder, io::AsyncWrite, net::UnixStream};
+use protociolla::{Codec, Vectored, Packet, packet::{Cookie, Header}};
+
+/// A writer that takes at most `limit` bytes per write, slices included.
+struct Partial {
+	limit: usize,
+	written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl AsyncWrite for Partial {
+	fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+		let length = buf.len().min(self.limit);
+		self.written.lock().unwrap().extend_from_slice(&buf[.. length]);
+
+		Poll::Ready(Ok(length))
+	}
+
+	fn poll_write_vectored(self: Pin<&mut Self>, _cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, io::Error>> {
+		let mut written = self.written.lock().unwrap();
+		let mut left = self.limit;
+
+		for buf in bufs {
+			let length = buf.len().min(left);
+			written.extend_from_slice(&buf[.. length]);
+			left -= length;
+
+			if left == 0 {
+				break;
+			}
+		}
+
+		Poll::Ready(Ok(self.limit - left))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+/// A payload spanning more fragments than fit in a single vectored write.
+fn payload() -> Bytes {
+	(0 .. 0xfffe * 70 + 123).map(|i| i as u8).collect::<Vec<u8>>().into()
+}
+
+fn fragments(cookie: u16, payload: &Bytes) -> Vec<(Header, Bytes)> {
+	let mut fragments = payload.chunks(0xfffe).map(|chunk| {
+		(Header::single(cookie, None), Bytes::from(chunk))
+	}).collect::<Vec<_>>();
+
+	let last = fragments.pop().unwrap().1;
+	fragments.push((Header::single(cookie, Some(last.len())), last));
+
+	fragments
+}
+
+async fn written(limit: usize, fragments: Vec<(Header, Bytes)>) -> Vec<u8> {
+	let written = Arc::new(Mutex::new(Vec::new()));
+	let mut sink = Vectored::new(Partial { limit, written: written.clone() });
+
+	sink.send_all(&mut stream::iter(fragments.into_iter().map(Ok))).await.unwrap();
+	sink.close().await.unwrap();
+
+	let written = written.lock().unwrap().clone();
+	written
+}
+
+fn encoded(fragments: Vec<(Header, Bytes)>) -> Vec<u8> {
+	let mut codec = Codec::default();
+	let mut buffer = BytesMut::new();
+
+	for fragment in fragments {
+		codec.encode(fragment, &mut buffer).unwrap();
+	}
+
+	buffer.to_vec()
+}
+
+#[tokio::test]
+async fn matches_codec() {
+	let fragments = fragments(1, &payload());
+	assert!(fragments.len() > 64);
+
+	assert_eq!(written(usize::max_value(), fragments.clone()).await, encoded(fragments));
+}
+
+#[tokio::test]
+async fn partial_writes() {
+	// Split headers and payloads at odd offsets.
+	let large = fragments(1, &payload());
+	assert_eq!(written(4099, large.clone()).await, encoded(large));
+
+	let small = fragments(2, &payload().slice_to(0xfffe * 2 + 5));
+	assert_eq!(written(3, small.clone()).await, encoded(small));
+}
+
+#[tokio::test]
+async fn write_zero() {
+	let mut sink = Vectored::new(Partial { limit: 0, written: Default::default() });
+	let error = sink.send((Header::oneshot(Some(1)), Bytes::from_static(b"a"))).await.unwrap_err();
+
+	assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi_vectored::<(), _>(left);
+	let mut right = protociolla::mi::<(), _>(right);
+
+	let payload = payload();
+	left.send(Packet::new(Cookie::Oneshot, payload.clone())).await.unwrap();
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from_static(b"small"))).await.unwrap();
+
+	let mut session = right.next().await.unwrap().unwrap();
+	assert_eq!(session.next().await.unwrap().bytes(), &payload);
+
+	let mut session = right.next().await.unwrap().unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], b"small");
+}
@@ -0,0 +1,64 @@
+#![cfg(feature = "websocket")]
+
+use std::io;
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+use protociolla::{Packet, packet::Cookie, websocket};
+
+/// Spawn a server echoing every oneshot packet back, returning its URL.
+async fn echo() -> String {
+	let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let url = format!("ws://{}", listener.local_addr().unwrap());
+
+	tokio::spawn(async move {
+		let (socket, _) = listener.accept().await.unwrap();
+		let mut server = websocket::accept::<(), _>(socket).await.unwrap();
+
+		while let Some(Ok(mut session)) = server.next().await {
+			let message = session.next().await.unwrap();
+			server.send(Packet::new(Cookie::Oneshot, message.bytes().clone())).await.unwrap();
+		}
+	});
+
+	url
+}
+
+#[tokio::test]
+async fn round_trip() {
+	let mut client = websocket::connect::<()>(&echo().await).await.unwrap();
+
+	// The last one spans multiple fragments, so multiple binary messages.
+	for &length in &[0, 1, 1000, 200_000] {
+		let payload = (0 .. length).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+		client.send(Packet::new(Cookie::Oneshot, Bytes::from(payload.clone()))).await.unwrap();
+
+		let mut session = client.next().await.unwrap().unwrap();
+		assert_eq!(&session.next().await.unwrap().bytes()[..], &payload[..]);
+	}
+}
+
+#[tokio::test]
+async fn bad_length() {
+	let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let url = format!("ws://{}", listener.local_addr().unwrap());
+
+	tokio::spawn(async move {
+		let (mut socket, _) = tokio_tungstenite::connect_async(url::Url::parse(&url).unwrap()).await.unwrap();
+
+		// The header claims 10 bytes of payload, only 2 follow.
+		socket.send(Message::Binary(vec![0, 0, 0, 10, 1, 2])).await.unwrap();
+	});
+
+	let (socket, _) = listener.accept().await.unwrap();
+	let mut server = websocket::accept::<(), _>(socket).await.unwrap();
+
+	match server.next().await {
+		Some(Err(error)) =>
+			assert_eq!(error.kind(), io::ErrorKind::InvalidData),
+
+		_ =>
+			panic!("expected a length mismatch"),
+	}
+}
@@ -0,0 +1,54 @@
+#![cfg(all(unix, feature = "zstd"))]
+
+use bytes::Bytes;
+use futures::{stream::StreamExt, sink::SinkExt};
+use tokio::net::UnixStream;
+use protociolla::{Packet, packet::Cookie, compress::{self, Options}};
+
+fn samples() -> Vec<Vec<u8>> {
+	(0 .. 1000)
+		.map(|i| format!(r#"{{"id":{},"name":"sensor-{}","healthy":true,"uptime":{}}}"#, i, i % 7, i * 31).into_bytes())
+		.collect()
+}
+
+async fn round_trip(options: Options) {
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi_zstd::<(), _>(left, options.clone()).unwrap();
+	let mut right = protociolla::mi_zstd::<(), _>(right, options).unwrap();
+
+	for sample in samples().into_iter().take(50) {
+		left.send(Packet::new(Cookie::Oneshot, Bytes::from(sample.clone()))).await.unwrap();
+
+		let mut session = right.next().await.unwrap().unwrap();
+		let message = session.next().await.unwrap();
+
+		assert_eq!(&message.bytes()[..], &sample[..]);
+	}
+}
+
+#[tokio::test]
+async fn without_dictionary() {
+	round_trip(Options::default()).await;
+}
+
+#[tokio::test]
+async fn with_dictionary() {
+	let dictionary = compress::train(&samples(), 4096).unwrap();
+	assert!(!dictionary.is_empty());
+
+	round_trip(Options { dictionary: Some(dictionary), .. Options::default() }).await;
+}
+
+#[tokio::test]
+async fn large_payload() {
+	let payload = (0 .. 200_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+	let (left, right) = UnixStream::pair().unwrap();
+	let mut left = protociolla::mi_zstd::<(), _>(left, Options::default()).unwrap();
+	let mut right = protociolla::mi_zstd::<(), _>(right, Options::default()).unwrap();
+
+	left.send(Packet::new(Cookie::Oneshot, Bytes::from(payload.clone()))).await.unwrap();
+
+	let mut session = right.next().await.unwrap().unwrap();
+	assert_eq!(&session.next().await.unwrap().bytes()[..], &payload[..]);
+}